@@ -0,0 +1 @@
+ALTER TABLE releases DROP CONSTRAINT releases_app_id_tag_key;
@@ -0,0 +1 @@
+ALTER TABLE releases ADD UNIQUE (app_id, tag);
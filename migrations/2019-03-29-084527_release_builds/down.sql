@@ -0,0 +1,18 @@
+ALTER TABLE releases
+    DROP COLUMN build_started,
+    DROP COLUMN build_finished,
+    DROP COLUMN build_status;
+
+UPDATE releases SET state = 'todo' WHERE state = 'failed';
+
+ALTER TYPE release_state RENAME TO release_state_old;
+CREATE TYPE release_state AS ENUM (
+    'todo',
+    'building',
+    'ready'
+);
+
+ALTER TABLE releases ALTER COLUMN state DROP DEFAULT;
+ALTER TABLE releases ALTER COLUMN state TYPE release_state USING state::text::release_state;
+ALTER TABLE releases ALTER COLUMN state SET DEFAULT 'todo'::release_state;
+DROP TYPE release_state_old;
@@ -0,0 +1,6 @@
+ALTER TYPE release_state ADD VALUE 'failed';
+
+ALTER TABLE releases
+    ADD COLUMN build_started timestamp with time zone,
+    ADD COLUMN build_finished timestamp with time zone,
+    ADD COLUMN build_status int;
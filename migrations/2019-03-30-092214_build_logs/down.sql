@@ -0,0 +1,2 @@
+DROP TABLE build_logs;
+DROP TYPE log_stream;
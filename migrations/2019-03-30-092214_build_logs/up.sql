@@ -0,0 +1,14 @@
+CREATE TYPE log_stream AS ENUM (
+    'stdout',
+    'stderr'
+);
+
+CREATE TABLE build_logs (
+    id int GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    release_id int NOT NULL REFERENCES releases(id) ON DELETE CASCADE,
+    created timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    stream log_stream NOT NULL,
+    line text NOT NULL
+);
+
+CREATE INDEX build_logs_release_id ON build_logs (release_id);
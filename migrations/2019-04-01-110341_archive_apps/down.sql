@@ -0,0 +1,10 @@
+ALTER TABLE releases DROP CONSTRAINT releases_app_id_fkey;
+ALTER TABLE releases
+    ADD CONSTRAINT releases_app_id_fkey
+    FOREIGN KEY (app_id) REFERENCES apps(id);
+
+DROP INDEX apps_live_name_key;
+ALTER TABLE apps ADD CONSTRAINT apps_name_key UNIQUE (name);
+
+ALTER TABLE releases DROP COLUMN archived;
+ALTER TABLE apps DROP COLUMN archived;
@@ -0,0 +1,13 @@
+-- Apps and releases are archived rather than deleted, so history is retained.
+ALTER TABLE apps ADD COLUMN archived timestamp with time zone;
+ALTER TABLE releases ADD COLUMN archived timestamp with time zone;
+
+-- Names only need to be unique amongst live apps, so archived names can be reused.
+ALTER TABLE apps DROP CONSTRAINT apps_name_key;
+CREATE UNIQUE INDEX apps_live_name_key ON apps (name) WHERE archived IS NULL;
+
+-- As rows are never deleted, make it explicit that deleting an app with releases is an error.
+ALTER TABLE releases DROP CONSTRAINT releases_app_id_fkey;
+ALTER TABLE releases
+    ADD CONSTRAINT releases_app_id_fkey
+    FOREIGN KEY (app_id) REFERENCES apps(id) ON DELETE RESTRICT;
@@ -0,0 +1,17 @@
+CREATE TABLE deployments (
+    id int GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    client_id int NOT NULL REFERENCES clients(id) ON DELETE RESTRICT,
+    release_id int NOT NULL REFERENCES releases(id) ON DELETE RESTRICT,
+    created timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    active boolean NOT NULL DEFAULT False,
+    error text
+);
+
+CREATE INDEX deployments_client_id ON deployments (client_id);
+CREATE INDEX deployments_release_id ON deployments (release_id);
+
+CREATE TRIGGER update_timestamp
+BEFORE UPDATE
+ON deployments
+FOR EACH ROW EXECUTE FUNCTION update_timestamp();
@@ -0,0 +1,2 @@
+DROP TABLE tokens;
+DROP TYPE token_role;
@@ -0,0 +1,22 @@
+CREATE TYPE token_role AS ENUM (
+    'target',
+    'reader',
+    'admin'
+);
+
+CREATE TABLE tokens (
+    id int GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+    name text NOT NULL,
+    created timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    hash text NOT NULL UNIQUE,
+    role token_role NOT NULL,
+    revoked timestamp with time zone
+);
+
+CREATE UNIQUE INDEX tokens_name ON tokens (name) WHERE revoked IS NULL;
+
+CREATE TRIGGER update_timestamp
+BEFORE UPDATE
+ON tokens
+FOR EACH ROW EXECUTE FUNCTION update_timestamp();
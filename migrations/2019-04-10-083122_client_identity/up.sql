@@ -0,0 +1,2 @@
+-- Clients keep the same identity across reconnects, so they keep the same row.
+ALTER TABLE clients ADD COLUMN identity uuid UNIQUE;
@@ -0,0 +1,3 @@
+-- Which run of the castle last changed the client's connected state, so that clients left
+-- connected by a castle that died can be told apart.
+ALTER TABLE clients ADD COLUMN boot uuid;
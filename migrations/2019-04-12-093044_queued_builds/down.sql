@@ -0,0 +1,14 @@
+UPDATE releases SET state = 'todo' WHERE state = 'queued';
+
+ALTER TYPE release_state RENAME TO release_state_old;
+CREATE TYPE release_state AS ENUM (
+    'todo',
+    'building',
+    'ready',
+    'failed'
+);
+
+ALTER TABLE releases ALTER COLUMN state DROP DEFAULT;
+ALTER TABLE releases ALTER COLUMN state TYPE release_state USING state::text::release_state;
+ALTER TABLE releases ALTER COLUMN state SET DEFAULT 'todo'::release_state;
+DROP TYPE release_state_old;
@@ -0,0 +1 @@
+ALTER TYPE release_state ADD VALUE 'queued' BEFORE 'building';
@@ -0,0 +1,257 @@
+//! The castle's API, shared between the server and clients.
+//!
+//! The castle implements the `Castle` trait, and `delegate` generates its RPC methods from the same
+//! definition, checking each against the roles allowed to call it. Clients use the implementation
+//! on `CastleClient`, which makes the calls over a connection. Method names, signatures and roles
+//! are all written once here, so changing one breaks either side until it's updated.
+
+use crate::client::Kind;
+use crate::db::{
+    models::{App, Client, Release, ReleaseLogs},
+    types::{ReleaseState, Role},
+};
+use crate::rpc::{app_error, param_list, Offload, RpcClient, RpcRemote};
+use crate::BusStats;
+use jsonrpc_core::{futures::Future, Error, Metadata, Params, Result as RpcResult};
+use jsonrpc_macros::IoDelegate;
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{from_value, json, Value};
+use uuid::Uuid;
+
+/// Oldest client version (major, minor) the castle still speaks to.
+const MIN_SUPPORTED: (u64, u64) = (0, 1);
+
+/// What the castle supports, for clients to adapt to.
+pub const CAPABILITIES: &[&str] = &[
+    "binary-v2",
+    "batch",
+    "bus-stats",
+    "cancel",
+    "clients",
+    "deploy",
+    "rollback",
+];
+
+/// Roles allowed to look at apps and releases.
+pub const READ: &[Role] = &[Role::Reader, Role::Admin];
+
+/// Roles allowed to change apps and releases, and deploy them.
+pub const ADMIN: &[Role] = &[Role::Admin];
+
+/// Roles allowed to receive deploys.
+pub const TARGET: &[Role] = &[Role::Target];
+
+/// Roles allowed to connect at all, before checking what kind of client they are.
+pub const ANY: &[Role] = &[Role::Reader, Role::Admin, Role::Target];
+
+/// Close code used when a client's version isn't supported.
+pub const INCOMPATIBLE: ws::CloseCode = ws::CloseCode::Other(4000);
+
+/// Close code used when a client's token is refused.
+pub const UNAUTHORIZED: ws::CloseCode = ws::CloseCode::Other(4001);
+
+/// The castle's reply to a client's greetings.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Greeting {
+    /// Castle version, in the same `Trebuchet/<version>` form clients send
+    pub version: String,
+
+    /// Features the castle supports
+    pub capabilities: Vec<String>,
+
+    /// Id the castle knows this connection by
+    pub connection: Uuid,
+}
+
+/// This build's version, as sent in greetings.
+pub fn app_version() -> String {
+    format!("Trebuchet/{}", env!("CARGO_PKG_VERSION"))
+}
+
+/// Parses the version out of a `Trebuchet/<major>.<minor>.<patch>` string.
+pub fn parse_version(app: &str) -> Option<(u64, u64, u64)> {
+    let prefix = "Trebuchet/";
+    if !app.starts_with(prefix) {
+        return None;
+    }
+
+    let mut parts = app[prefix.len()..].splitn(3, '.').map(|part| {
+        part.chars()
+            .take_while(char::is_ascii_digit)
+            .collect::<String>()
+            .parse::<u64>()
+            .ok()
+    });
+
+    Some((parts.next()??, parts.next()??, parts.next()??))
+}
+
+/// Checks whether a client's version is one the castle can talk to.
+pub fn check_version(app: &str) -> Result<(), String> {
+    let current = parse_version(&app_version()).expect("own version is unparseable");
+    let (major, minor, _) =
+        parse_version(app).ok_or_else(|| format!("unrecognised client version {:?}", app))?;
+
+    if (major, minor) < MIN_SUPPORTED || (major, minor) > (current.0, current.1) {
+        Err(format!(
+            "client version {}.{} is outside the supported range {}.{} to {}.{}",
+            major, minor, MIN_SUPPORTED.0, MIN_SUPPORTED.1, current.0, current.1
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// How the castle decides whether a connection may make a call.
+pub trait Permit {
+    /// Errors unless the connection authenticated with one of the given roles.
+    fn permit(&self, roles: &[Role]) -> RpcResult<()>;
+}
+
+/// Positional parameters of a call, taken in order.
+struct Args(std::vec::IntoIter<Value>);
+
+impl Args {
+    fn new(params: Params) -> RpcResult<Self> {
+        match params {
+            Params::Array(values) => Ok(Args(values.into_iter())),
+            Params::None => Ok(Args(Vec::new().into_iter())),
+            Params::Map(_) => Err(Error::invalid_params("expected positional parameters")),
+        }
+    }
+
+    /// Missing trailing parameters are taken as null, so optional ones can be left out.
+    fn next<T: DeserializeOwned>(&mut self, name: &str) -> RpcResult<T> {
+        from_value(self.0.next().unwrap_or(Value::Null))
+            .map_err(|err| Error::invalid_params(format!("{}: {}", name, err)))
+    }
+}
+
+macro_rules! castle_api {
+    ($(
+        $(#[doc = $doc:expr])*
+        $role:ident $rpc:tt fn $method:ident(&self $(, $arg:ident: $ty:ty)*) -> $ret:ty;
+    )*) => {
+        pub trait Castle {
+            $(
+                $(#[doc = $doc])*
+                fn $method(&self $(, $arg: $ty)*) -> RpcResult<$ret>;
+            )*
+        }
+
+        impl Castle for CastleClient {
+            $(
+                fn $method(&self $(, $arg: $ty)*) -> RpcResult<$ret> {
+                    self.call($rpc, vec![$(json!($arg)),*])
+                }
+            )*
+        }
+
+        /// Adds the castle's API methods to an RPC delegate.
+        ///
+        /// Each checks the connection is permitted to call it, then forwards to `Castle`. They can
+        /// take a while (on the data service, on git, on disk), so they run in turn on a thread of
+        /// their own rather than on the websocket thread.
+        pub fn delegate<T, M>(delegate: &mut IoDelegate<T, M>)
+        where
+            T: Castle + Permit + Clone + Send + Sync + 'static,
+            M: Metadata,
+        {
+            let calls = Offload::new("castle calls");
+            $(
+                let offload = calls.clone();
+                delegate.add_method($rpc, move |castle: &T, params: Params| {
+                    let castle = castle.clone();
+                    offload.run(move || {
+                        castle.permit($role)?;
+
+                        #[allow(unused_mut)]
+                        let mut args = Args::new(params)?;
+                        $(let $arg: $ty = args.next(stringify!($arg))?;)*
+                        castle.$method($($arg),*).map(|ret| json!(ret))
+                    })
+                });
+            )*
+        }
+    };
+}
+
+castle_api! {
+    /// Checks a client's version and role, and records it as connected.
+    ANY "greetings" fn greetings(&self, app: String, kind: Kind, name: String, tags: Vec<String>, identity: Uuid) -> Greeting;
+
+    /// Lists live apps, optionally filtered by a regexp over their names.
+    READ "apps:list" fn apps_list(&self, filter: Option<String>) -> Vec<App>;
+
+    /// Configures a new app.
+    ADMIN "apps:create" fn apps_create(&self, name: String, repo: String, build_script: Option<String>) -> App;
+
+    /// Archives an app, refusing if it has releases or deployments unless forced.
+    ADMIN "apps:delete" fn apps_delete(&self, name: String, force: bool) -> App;
+
+    /// Reconfigures an app, leaving out what isn't given.
+    ADMIN "apps:update" fn apps_update(&self, name: String, new_name: Option<String>, repo: Option<String>, build_script: Option<String>) -> App;
+
+    /// Fetches the app's repo and creates releases for new tags.
+    ADMIN "releases:sync" fn releases_sync(&self, app: String) -> Vec<Release>;
+
+    /// Lists an app's releases, optionally filtered by a regexp over tags and by state.
+    READ "releases:list" fn releases_list(&self, app: String, filter: Option<String>, status: Option<ReleaseState>) -> Vec<Release>;
+
+    /// Queues the build of a release that hasn't been built.
+    ADMIN "releases:build" fn releases_build(&self, app: String, tag: String) -> Release;
+
+    /// Queues the build of a release, even if it's been built.
+    ADMIN "releases:rebuild" fn releases_rebuild(&self, app: String, tag: String) -> Release;
+
+    /// Returns a release's build log so far, and streams the rest if it's still to come.
+    READ "releases:follow" fn releases_follow(&self, app: String, tag: String) -> ReleaseLogs;
+
+    /// Deploys a ready release to targets selected by name or tags, returning their names.
+    ADMIN "deploy" fn deploy(&self, app: String, tag: String, names: Vec<String>, tags: Vec<String>) -> Vec<String>;
+
+    /// Re-activates the previous (or a given) release on targets, returning each target and tag.
+    ADMIN "rollback" fn rollback(&self, app: String, to: Option<String>, names: Vec<String>, tags: Vec<String>) -> Vec<(String, String)>;
+
+    /// Lists clients by kind, name regexp, tags (any of), and connected state, and optionally
+    /// streams their comings and goings (regardless of state) as `clients:presence`.
+    READ "clients:list" fn clients_list(&self, kind: Option<Kind>, filter: Option<String>, tags: Vec<String>, connected: Option<bool>, watch: bool) -> Vec<Client>;
+
+    /// Counts messages the castle dropped because connections couldn't keep up.
+    ADMIN "bus:stats" fn bus_stats(&self) -> BusStats;
+
+    /// Reads a chunk of a release's artefact from an offset, as binary data under `.raw`, along
+    /// with the artefact's total size and checksum.
+    TARGET "artefacts:read" fn artefacts_read(&self, release: i32, offset: u64) -> Value;
+}
+
+/// Typed calls to the castle over a connection.
+///
+/// Calls wait for their response, so only use this off the websocket thread.
+#[derive(Clone)]
+pub struct CastleClient {
+    remote: RpcRemote,
+}
+
+impl CastleClient {
+    pub fn new(remote: RpcRemote) -> Self {
+        Self { remote }
+    }
+
+    fn call<T: DeserializeOwned>(&self, method: &str, params: Vec<Value>) -> RpcResult<T> {
+        let value = self
+            .remote
+            .call_async(method, param_list(params), &[])
+            .map_err(|err| app_error(64, "failed to send call", Some(json!(err.to_string()))))?
+            .wait()?;
+
+        from_value(value).map_err(|err| {
+            app_error(
+                66,
+                "unexpected response from castle",
+                Some(json!({ "method": method, "error": err.to_string() })),
+            )
+        })
+    }
+}
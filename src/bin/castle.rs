@@ -5,14 +5,53 @@ use trebuchet::castle;
 
 fn main() {
     let args = castle::arguments().get_matches();
-    let (server, bus, terminal) = castle::init(&args);
+    let (server, tls, bus, terminal) = castle::init(&args);
+
+    let admin = if let Some(name) = args.value_of("new-token") {
+        let role = args
+            .value_of("role")
+            .expect("bad --role option")
+            .parse()
+            .expect("bad --role option");
+        Some(castle::create_token(&bus, name, role).map(|token| println!("{}", token)))
+    } else if let Some(name) = args.value_of("revoke-token") {
+        Some(castle::revoke_token(&bus, name))
+    } else {
+        None
+    };
+
+    if let Some(res) = admin {
+        bus.kill();
+        terminal.join().unwrap();
+        if let Err(err) = res {
+            eprintln!("{}: {:?}", err.message, err.data);
+            std::process::exit(1);
+        }
+
+        return;
+    }
+
+    let settings = ws::Settings {
+        encrypt_server: tls.is_some(),
+        ..ws::Settings::default()
+    };
 
     // Larnach Castle postcode
-    ws::listen(server, |wstx| {
-        let bus = bus.clone().launch();
-        castle::Server::create(castle::Rpc::new(bus.clone()), wstx, bus)
-    })
-    .unwrap();
+    ws::Builder::new()
+        .with_settings(settings)
+        .build(|wstx: ws::Sender| {
+            let bus = bus.clone().launch();
+            let session = castle::Session::default();
+            castle::Server::create(
+                castle::Rpc::new(bus.clone(), wstx.clone(), session.clone()),
+                wstx,
+                bus,
+                session,
+                tls.clone(),
+            )
+        })
+        .and_then(|socket| socket.listen(server))
+        .unwrap();
     bus.kill();
     terminal.join().unwrap();
 }
@@ -5,13 +5,14 @@ use trebuchet::client::{command, init, Client, Kind};
 
 fn main() {
     let args = command::arguments().get_matches();
-    let (server, name, tags) = init(&args);
+    let (server, access, name, tags) = init(&args);
 
     ws::connect(server, |sender| {
         let args = args.clone();
         Client::create(
-            command::Rpc,
+            command::Rpc::new(sender.clone()),
             sender,
+            access.clone(),
             Kind::Command,
             name.clone(),
             tags.clone(),
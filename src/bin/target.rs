@@ -1,22 +1,25 @@
 #![forbid(unsafe_code)]
 #![deny(clippy::pedantic)]
 
-use trebuchet::client::{init, target, Client, Kind};
+use crossbeam_channel::unbounded;
+use trebuchet::client::{init, reconnect, target, Client, Kind};
 
 fn main() {
     let args = target::arguments().get_matches();
-    let (server, name, tags) = init(&args);
+    let (server, access, name, tags) = init(&args);
 
-    ws::connect(server, |sender| {
+    reconnect(&server, |sender| {
+        // the job queue goes away with the connection, which ends the body
+        let (jobs_tx, jobs_rx) = unbounded();
         let args = args.clone();
         Client::create(
-            target::Rpc,
+            target::Rpc::new(jobs_tx),
             sender,
+            access.clone(),
             Kind::Target,
             name.clone(),
             tags.clone(),
-            move |remote| target::handler(remote, args.clone()),
+            move |remote| target::handler(remote, args.clone(), jobs_rx.clone()),
         )
-    })
-    .expect("failed to start websocket client");
+    });
 }
@@ -1,19 +1,261 @@
-use crossbeam_channel::{unbounded, Receiver, Sender, TrySendError};
-use log::{debug, trace};
-use std::collections::HashMap;
+use crossbeam_channel::{
+    bounded, select, unbounded, Receiver, RecvTimeoutError, SendTimeoutError, Sender, TrySendError,
+};
+use log::{debug, trace, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::env;
 use std::fmt::Debug;
+use std::sync::{Arc, Mutex, Weak};
 use std::thread::{Builder, JoinHandle};
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
+const DEFAULT_CAPACITY: usize = 1024;
+
+/// How long central waits on a full blocking bus before giving up on it.
+const BLOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often central checks the full blocking bus is still there while it waits.
+const BLOCK_CHECK: Duration = Duration::from_millis(100);
+
+/// What central does with a broadcast or published message for a bus that's full.
+///
+/// Direct messages and requests are queued apart, without limit, so they're never dropped.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Overflow {
+    /// Wait for room, holding up every other message meanwhile
+    ///
+    /// If the bus goes away or makes no room for 30 seconds, it's disconnected instead.
+    Block,
+
+    /// Make room by dropping the oldest message waiting
+    DropOldest,
+
+    /// Give up on the bus: its receiving end sees the bus end once it's drained
+    Disconnect,
+}
+
+/// How many broadcast or published messages a bus can have waiting, and what happens past that.
+#[derive(Clone, Copy, Debug)]
+pub struct Limit {
+    pub capacity: usize,
+    pub overflow: Overflow,
+}
+
+impl Limit {
+    /// For services that must see every message.
+    pub fn blocking() -> Self {
+        Self {
+            capacity: DEFAULT_CAPACITY,
+            overflow: Overflow::Block,
+        }
+    }
+}
+
+impl Default for Limit {
+    /// From `TREBUCHET_BUS_CAPACITY` and `TREBUCHET_BUS_OVERFLOW`, dropping the oldest otherwise.
+    fn default() -> Self {
+        let capacity = env::var("TREBUCHET_BUS_CAPACITY")
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(DEFAULT_CAPACITY);
+
+        let overflow = match env::var("TREBUCHET_BUS_OVERFLOW")
+            .as_ref()
+            .map(String::as_str)
+        {
+            Ok("block") => Overflow::Block,
+            Ok("disconnect") => Overflow::Disconnect,
+            _ => Overflow::DropOldest,
+        };
+
+        Self {
+            capacity: capacity.max(1),
+            overflow,
+        }
+    }
+}
+
+/// Counters of messages lost to full buses.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct BusStats {
+    /// Messages dropped, over all buses ever
+    pub dropped: usize,
+
+    /// Buses disconnected for being full
+    pub disconnected: usize,
+
+    /// Messages dropped, by live bus
+    pub buses: HashMap<Uuid, usize>,
+}
+
+/// A message as received, with what's needed to answer it if it's a request.
+#[derive(Clone, Debug)]
+pub struct Incoming<T> {
+    /// Id of the bus it came from
+    pub source: Uuid,
+
+    pub content: T,
+
+    /// Correlation id to reply to, if the sender is waiting on a reply
+    pub request: Option<Uuid>,
+}
+
+/// Why a request got no reply.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RequestError {
+    /// The target doesn't exist, or went away before replying
+    Gone,
+
+    /// No reply came in time
+    Timeout,
+}
+
+/// Central's end of a bus.
+struct Route<T> {
+    tx: Sender<Incoming<T>>,
+
+    /// Kept to drop the oldest messages from, so it can't tell whether the bus is gone
+    rx: Receiver<Incoming<T>>,
+
+    /// Unbounded, for direct messages and requests
+    direct: Sender<Incoming<T>>,
+
+    /// Gone once every copy of the bus is
+    alive: Weak<Alive<T>>,
+
+    overflow: Overflow,
+}
+
+/// Shared by all copies of a bus, and tells central when the last one goes.
+#[derive(Debug)]
+struct Alive<T> {
+    id: Uuid,
+    to_central: Sender<Envelope<T>>,
+}
+
+impl<T> Drop for Alive<T> {
+    fn drop(&mut self) {
+        trace!("last copy of bus {} dropped", self.id);
+        self.to_central
+            .try_send(Envelope::Gone {
+                id: self.id.clone(),
+            })
+            .ok();
+    }
+}
+
+impl<T> Incoming<T> {
+    fn new(source: Uuid, content: T) -> Self {
+        Self {
+            source,
+            content,
+            request: None,
+        }
+    }
+}
+
+impl<T> Route<T> {
+    /// Delivers a direct message or request. Returns false if the bus is done for.
+    fn deliver_direct(&self, msg: Incoming<T>) -> bool {
+        self.alive.upgrade().is_some() && self.direct.send(msg).is_ok()
+    }
+
+    /// Delivers a message according to the overflow policy. Returns false if the bus is done for.
+    fn deliver(&self, id: &Uuid, msg: Incoming<T>, stats: &Mutex<BusStats>) -> bool {
+        if self.alive.upgrade().is_none() {
+            return false;
+        }
+
+        let msg = match self.tx.try_send(msg) {
+            Ok(()) => return true,
+            Err(TrySendError::Disconnected(_)) => return false,
+            Err(TrySendError::Full(msg)) => msg,
+        };
+
+        match self.overflow {
+            Overflow::Block => self.wait_for_room(id, msg, stats),
+            Overflow::DropOldest => {
+                // central is the only sender, so there's room after this
+                self.rx.try_recv().ok();
+                let mut stats = stats.lock().expect("bus stats lock poisoned");
+                stats.dropped += 1;
+                *stats.buses.entry(id.clone()).or_default() += 1;
+                self.tx.try_send(msg).is_ok()
+            }
+            Overflow::Disconnect => {
+                warn!("bus {} is full, disconnecting it", id);
+                let mut stats = stats.lock().expect("bus stats lock poisoned");
+                stats.dropped += 1;
+                stats.disconnected += 1;
+                false
+            }
+        }
+    }
+
+    /// Waits for a full bus to make room, unless it goes away or takes too long.
+    ///
+    /// Central keeps a receiving end, so sending alone would never notice the bus is gone.
+    fn wait_for_room(&self, id: &Uuid, mut msg: Incoming<T>, stats: &Mutex<BusStats>) -> bool {
+        let deadline = Instant::now() + BLOCK_TIMEOUT;
+        loop {
+            msg = match self.tx.send_timeout(msg, BLOCK_CHECK) {
+                Ok(()) => return true,
+                Err(SendTimeoutError::Disconnected(_)) => return false,
+                Err(SendTimeoutError::Timeout(msg)) => msg,
+            };
+
+            if self.alive.upgrade().is_none() {
+                debug!("bus {} went away while full, disconnecting it", id);
+                break;
+            }
+
+            if Instant::now() >= deadline {
+                warn!(
+                    "bus {} made no room in {:?}, disconnecting it",
+                    id, BLOCK_TIMEOUT
+                );
+                break;
+            }
+        }
+
+        let mut stats = stats.lock().expect("bus stats lock poisoned");
+        stats.dropped += 1;
+        stats.disconnected += 1;
+        false
+    }
+}
+
 pub fn central<T: 'static + Clone + Debug + Send>() -> (Bus<T>, JoinHandle<()>) {
+    // Unbounded: central itself may be held up by a blocking bus, and it must not in turn hold up
+    // whoever is sending, as that could be the very bus it's waiting on.
     let (central_tx, central_rx) = unbounded(); // enveloped
 
     let id = Uuid::default();
-    let (bus_tx, bus_rx) = unbounded(); // bare
+    let limit = Limit::blocking();
+    let (bus_tx, bus_rx) = bounded(limit.capacity); // bare
+    let (direct_tx, direct_rx) = unbounded(); // bare
+    let alive = Arc::new(Alive {
+        id: id.clone(),
+        to_central: central_tx.clone(),
+    });
+    let stats = Arc::new(Mutex::new(BusStats::default()));
+    let top = Route {
+        tx: bus_tx,
+        rx: bus_rx.clone(),
+        direct: direct_tx,
+        alive: Arc::downgrade(&alive),
+        overflow: limit.overflow,
+    };
+
     let bus = Bus {
         id: id.clone(),
         to_central: central_tx,
         rx: bus_rx,
+        direct: direct_rx,
+        alive,
+        stats: stats.clone(),
     };
 
     (
@@ -21,8 +263,14 @@ pub fn central<T: 'static + Clone + Debug + Send>() -> (Bus<T>, JoinHandle<()>)
         Builder::new()
             .name("bus central".into())
             .spawn(move || {
-                let mut switch: HashMap<Uuid, Sender<(Uuid, T)>> = HashMap::new();
-                switch.insert(id, bus_tx);
+                let mut switch: HashMap<Uuid, Route<T>> = HashMap::new();
+                switch.insert(id, top);
+
+                // topic => subscribed buses
+                let mut topics: HashMap<String, HashSet<Uuid>> = HashMap::new();
+
+                // request id => (target, where the reply goes)
+                let mut pending: HashMap<Uuid, (Uuid, Sender<T>)> = HashMap::new();
 
                 for envelope in central_rx.iter() {
                     trace!("message on the bus: {:?}", envelope);
@@ -30,10 +278,12 @@ pub fn central<T: 'static + Clone + Debug + Send>() -> (Bus<T>, JoinHandle<()>)
                     match envelope {
                         Envelope::Exit => break,
                         Envelope::Broadcast { source, content } => {
-                            for (id, tx) in &switch {
-                                if let Err(TrySendError::Disconnected(_)) =
-                                    tx.try_send((source, content.clone()))
-                                {
+                            for (id, route) in &switch {
+                                if !route.deliver(
+                                    id,
+                                    Incoming::new(source, content.clone()),
+                                    &stats,
+                                ) {
                                     dead.push(id.clone());
                                 }
                             }
@@ -43,16 +293,96 @@ pub fn central<T: 'static + Clone + Debug + Send>() -> (Bus<T>, JoinHandle<()>)
                             target,
                             content,
                         } => {
-                            if let Some(tx) = switch.get(&target) {
-                                if let Err(TrySendError::Disconnected(_)) =
-                                    tx.try_send((source, content))
-                                {
+                            if let Some(route) = switch.get(&target) {
+                                if !route.deliver_direct(Incoming::new(source, content)) {
+                                    dead.push(target.clone());
+                                }
+                            }
+                        }
+                        Envelope::Publish {
+                            source,
+                            topic,
+                            content,
+                        } => {
+                            for id in topics.get(&topic).into_iter().flatten() {
+                                if let Some(route) = switch.get(id) {
+                                    if !route.deliver(
+                                        id,
+                                        Incoming::new(source, content.clone()),
+                                        &stats,
+                                    ) {
+                                        dead.push(id.clone());
+                                    }
+                                }
+                            }
+                        }
+                        Envelope::Request {
+                            source,
+                            target,
+                            id,
+                            content,
+                            reply,
+                        } => {
+                            let incoming = Incoming {
+                                source,
+                                content,
+                                request: Some(id),
+                            };
+
+                            // dropping the reply end tells the requester the target is gone
+                            if let Some(route) = switch.get(&target) {
+                                if route.deliver_direct(incoming) {
+                                    pending.insert(id, (target, reply));
+                                } else {
                                     dead.push(target.clone());
                                 }
                             }
                         }
-                        Envelope::Launch { id, tx } => {
-                            switch.insert(id, tx);
+                        Envelope::Reply { id, content } => {
+                            if let Some((_, reply)) = pending.remove(&id) {
+                                reply.try_send(content).ok();
+                            }
+                        }
+                        Envelope::Forget { id } => {
+                            pending.remove(&id);
+                        }
+                        Envelope::Gone { id } => {
+                            // the id may have been launched again since
+                            if let Some(route) = switch.get(&id) {
+                                if route.alive.upgrade().is_none() {
+                                    dead.push(id);
+                                }
+                            }
+                        }
+                        Envelope::Launch {
+                            id,
+                            tx,
+                            rx,
+                            direct,
+                            alive,
+                            overflow,
+                        } => {
+                            switch.insert(
+                                id,
+                                Route {
+                                    tx,
+                                    rx,
+                                    direct,
+                                    alive,
+                                    overflow,
+                                },
+                            );
+                        }
+                        Envelope::Subscribe { id, topic } => {
+                            topics.entry(topic).or_default().insert(id);
+                        }
+                        Envelope::Unsubscribe { id, topic } => {
+                            if let Some(subscribers) = topics.get_mut(&topic) {
+                                subscribers.remove(&id);
+                                if subscribers.is_empty() {
+                                    topics.remove(&topic);
+                                }
+                            }
                         }
                     }
 
@@ -62,6 +392,18 @@ pub fn central<T: 'static + Clone + Debug + Send>() -> (Bus<T>, JoinHandle<()>)
                     for id in &dead {
                         switch.remove(id);
                     }
+                    if !dead.is_empty() {
+                        topics.retain(|_, subscribers| {
+                            subscribers.retain(|id| !dead.contains(id));
+                            !subscribers.is_empty()
+                        });
+                        pending.retain(|_, (target, _)| !dead.contains(target));
+
+                        let mut stats = stats.lock().expect("bus stats lock poisoned");
+                        for id in &dead {
+                            stats.buses.remove(id);
+                        }
+                    }
                 }
             })
             .expect("failed to start bus central"),
@@ -74,11 +416,17 @@ pub struct Bus<T> {
     pub id: Uuid,
 
     to_central: Sender<Envelope<T>>,
-    rx: Receiver<(Uuid, T)>,
+    rx: Receiver<Incoming<T>>,
+    direct: Receiver<Incoming<T>>,
+
+    /// Shared by all copies of this bus, so central can tell when they're all gone
+    alive: Arc<Alive<T>>,
+
+    stats: Arc<Mutex<BusStats>>,
 }
 
 #[derive(Clone, Debug)]
-pub enum Envelope<T> {
+enum Envelope<T> {
     Exit,
     Broadcast {
         source: Uuid,
@@ -89,25 +437,87 @@ pub enum Envelope<T> {
         target: Uuid,
         content: T,
     },
+    Request {
+        source: Uuid,
+        target: Uuid,
+        id: Uuid,
+        content: T,
+        reply: Sender<T>,
+    },
+    Reply {
+        id: Uuid,
+        content: T,
+    },
+    Forget {
+        id: Uuid,
+    },
+    Gone {
+        id: Uuid,
+    },
     Launch {
         id: Uuid,
-        tx: Sender<(Uuid, T)>,
+        tx: Sender<Incoming<T>>,
+        rx: Receiver<Incoming<T>>,
+        direct: Sender<Incoming<T>>,
+        alive: Weak<Alive<T>>,
+        overflow: Overflow,
+    },
+    Publish {
+        source: Uuid,
+        topic: String,
+        content: T,
+    },
+    Subscribe {
+        id: Uuid,
+        topic: String,
+    },
+    Unsubscribe {
+        id: Uuid,
+        topic: String,
     },
 }
 
 impl<T: 'static + Clone + Debug + Send> Bus<T> {
-    pub fn launch(mut self) -> Self {
-        let id = Uuid::new_v4();
-        debug!("new bus: {}", id);
+    /// Starts a new bus, with the default (configured) limit.
+    pub fn launch(self) -> Self {
+        self.launch_with(Limit::default())
+    }
+
+    pub fn launch_with(self, limit: Limit) -> Self {
+        self.launch_as(Uuid::new_v4(), limit)
+    }
 
-        let (tx, rx) = unbounded();
-        self.send(Envelope::Launch { id: id.clone(), tx });
+    /// Starts a new bus with a known id, for services others make requests to.
+    pub fn launch_as(mut self, id: Uuid, limit: Limit) -> Self {
+        debug!("new bus: {} ({:?})", id, limit);
+
+        let (tx, rx) = bounded(limit.capacity);
+        let (direct, direct_rx) = unbounded();
+        let alive = Arc::new(Alive {
+            id: id.clone(),
+            to_central: self.to_central.clone(),
+        });
+        self.send(Envelope::Launch {
+            id: id.clone(),
+            tx,
+            rx: rx.clone(),
+            direct,
+            alive: Arc::downgrade(&alive),
+            overflow: limit.overflow,
+        });
 
         self.id = id;
         self.rx = rx;
+        self.direct = direct_rx;
+        self.alive = alive;
         self
     }
 
+    /// Counters of messages lost to full buses, across the whole bus.
+    pub fn stats(&self) -> BusStats {
+        self.stats.lock().expect("bus stats lock poisoned").clone()
+    }
+
     /// Shut down the entire bus
     pub fn kill(self) {
         debug!("killing the bus");
@@ -136,6 +546,10 @@ impl<T: 'static + Clone + Debug + Send> Bus<T> {
         self.send_to(&Uuid::default(), msg)
     }
 
+    pub fn request_top(&self, msg: T, timeout: Duration) -> Result<T, RequestError> {
+        self.request(&Uuid::default(), msg, timeout)
+    }
+
     pub fn send_own(&self, msg: T) {
         self.send_to(&self.id, msg)
     }
@@ -147,11 +561,254 @@ impl<T: 'static + Clone + Debug + Send> Bus<T> {
         })
     }
 
+    /// Sends to every bus subscribed to a topic, if any.
+    pub fn publish(&self, topic: &str, msg: T) {
+        self.send(Envelope::Publish {
+            source: self.id.clone(),
+            topic: topic.into(),
+            content: msg,
+        })
+    }
+
+    /// Starts receiving what's published on a topic.
+    pub fn subscribe(&self, topic: &str) {
+        self.send(Envelope::Subscribe {
+            id: self.id.clone(),
+            topic: topic.into(),
+        })
+    }
+
+    pub fn unsubscribe(&self, topic: &str) {
+        self.send(Envelope::Unsubscribe {
+            id: self.id.clone(),
+            topic: topic.into(),
+        })
+    }
+
+    /// Sends to a bus and waits for its reply.
+    ///
+    /// Fails early if the target doesn't exist, or goes away (every copy of its bus dropped) before
+    /// replying.
+    pub fn request(&self, target: &Uuid, msg: T, timeout: Duration) -> Result<T, RequestError> {
+        let id = Uuid::new_v4();
+        let (reply, rx) = bounded(1);
+        self.send(Envelope::Request {
+            source: self.id.clone(),
+            target: target.clone(),
+            id,
+            content: msg,
+            reply,
+        });
+
+        match rx.recv_timeout(timeout) {
+            Ok(reply) => Ok(reply),
+            Err(RecvTimeoutError::Disconnected) => Err(RequestError::Gone),
+            Err(RecvTimeoutError::Timeout) => {
+                self.send(Envelope::Forget { id });
+                Err(RequestError::Timeout)
+            }
+        }
+    }
+
+    /// Answers a request received with `incoming()`.
+    ///
+    /// Replies to requests that timed out or whose requester is gone are dropped.
+    pub fn reply(&self, request: Uuid, msg: T) {
+        self.send(Envelope::Reply {
+            id: request,
+            content: msg,
+        })
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
-        self.rx.iter().map(|(_, c)| c)
+        self.incoming().map(|incoming| incoming.content)
     }
 
     pub fn iter_with_source(&self) -> impl Iterator<Item = (Uuid, T)> + '_ {
-        self.rx.iter()
+        self.incoming()
+            .map(|incoming| (incoming.source, incoming.content))
+    }
+
+    /// Everything received, including requests to reply to.
+    ///
+    /// Direct messages and requests are queued apart from the rest, so they may come in ahead of
+    /// broadcast or published messages sent before them.
+    pub fn incoming(&self) -> impl Iterator<Item = Incoming<T>> + '_ {
+        Receiving { bus: self }
+    }
+
+    /// Waits for a message on either queue, until both are disconnected and drained.
+    fn recv(&self) -> Option<Incoming<T>> {
+        select! {
+            recv(self.direct) -> msg => msg.ok().or_else(|| self.rx.recv().ok()),
+            recv(self.rx) -> msg => msg.ok().or_else(|| self.direct.recv().ok()),
+        }
+    }
+}
+
+struct Receiving<'bus, T> {
+    bus: &'bus Bus<T>,
+}
+
+impl<'bus, T: 'static + Clone + Debug + Send> Iterator for Receiving<'bus, T> {
+    type Item = Incoming<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.bus.recv()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Instant;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn dropping(capacity: usize) -> Limit {
+        Limit {
+            capacity,
+            overflow: Overflow::DropOldest,
+        }
+    }
+
+    /// Waits for central to have dropped so many messages.
+    fn settle<T: 'static + Clone + Debug + Send>(bus: &Bus<T>, dropped: usize) {
+        let deadline = Instant::now() + TIMEOUT;
+        while bus.stats().dropped < dropped {
+            assert!(Instant::now() < deadline, "central never dropped enough");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn drop_oldest_keeps_direct_messages() {
+        let (top, _) = central::<u32>();
+        let bus = top.clone().launch_with(dropping(2));
+
+        for n in 0..3 {
+            top.broadcast(n);
+        }
+        top.send_to(&bus.id, 10);
+        top.send_to(&bus.id, 11);
+        for n in 3..6 {
+            top.publish("nothing", n);
+            top.broadcast(n);
+        }
+
+        settle(&top, 4);
+        let mut received: Vec<u32> = bus.iter().take(4).collect();
+        received.sort_unstable();
+        assert_eq!(received, vec![4, 5, 10, 11]);
+        assert!(bus.rx.is_empty() && bus.direct.is_empty());
+        assert_eq!(top.stats().buses.get(&bus.id), Some(&4));
+    }
+
+    #[test]
+    fn drop_oldest_keeps_requests() {
+        let (top, _) = central::<u32>();
+        let service = top.clone().launch_with(dropping(1));
+
+        let id = Uuid::new_v4();
+        let (reply, replied) = bounded(1);
+        top.send(Envelope::Request {
+            source: top.id.clone(),
+            target: service.id.clone(),
+            id,
+            content: 100,
+            reply,
+        });
+        for n in 0..10 {
+            top.broadcast(n);
+        }
+
+        settle(&top, 9);
+        let mut broadcasts = Vec::new();
+        for incoming in service.incoming() {
+            match incoming.request {
+                Some(request) => {
+                    assert_eq!(request, id);
+                    service.reply(request, incoming.content + 1);
+                    break;
+                }
+                None => broadcasts.push(incoming.content),
+            }
+        }
+
+        assert_eq!(replied.recv_timeout(TIMEOUT), Ok(101));
+        assert!(broadcasts.len() <= 1);
+    }
+
+    #[test]
+    fn requests_fail_for_unknown_buses() {
+        let (top, _) = central::<u32>();
+        let start = Instant::now();
+        assert_eq!(
+            top.request(&Uuid::new_v4(), 1, TIMEOUT),
+            Err(RequestError::Gone)
+        );
+        assert!(start.elapsed() < TIMEOUT);
+    }
+
+    #[test]
+    fn requests_fail_when_target_goes_away() {
+        let (top, _) = central::<u32>();
+        let service = top.clone().launch();
+        let id = service.id.clone();
+        thread::spawn(move || {
+            // takes the request, then goes away without replying
+            service.incoming().next();
+        });
+
+        let start = Instant::now();
+        assert_eq!(top.request(&id, 1, TIMEOUT), Err(RequestError::Gone));
+        assert!(start.elapsed() < TIMEOUT);
+    }
+
+    #[test]
+    fn buses_outlive_some_copies() {
+        let (top, _) = central::<u32>();
+        let service = top.clone().launch();
+        let id = service.id.clone();
+        drop(service.clone());
+
+        thread::spawn(move || {
+            for incoming in service.incoming() {
+                if let Some(request) = incoming.request {
+                    service.reply(request, incoming.content + 1);
+                }
+            }
+        });
+
+        assert_eq!(top.request(&id, 1, TIMEOUT), Ok(2));
+    }
+
+    #[test]
+    fn full_blocking_bus_gives_way_when_dropped() {
+        let (top, _) = central::<u32>();
+        let stuck = top.clone().launch_with(Limit {
+            capacity: 1,
+            overflow: Overflow::Block,
+        });
+        let other = top.clone().launch();
+
+        // the second broadcast holds up central, as nothing reads the stuck bus
+        top.broadcast(1);
+        top.broadcast(2);
+        assert_eq!(other.rx.recv_timeout(TIMEOUT).map(|i| i.content), Ok(1));
+        thread::sleep(BLOCK_CHECK * 2);
+        drop(stuck);
+
+        top.send_to(&other.id, 10);
+        assert_eq!(other.rx.recv_timeout(TIMEOUT).map(|i| i.content), Ok(2));
+        assert_eq!(
+            other.direct.recv_timeout(TIMEOUT).map(|i| i.content),
+            Ok(10)
+        );
+
+        let stats = top.stats();
+        assert_eq!(stats.disconnected, 1);
+        assert_eq!(stats.dropped, 1);
     }
 }
@@ -20,6 +20,54 @@ pub fn arguments<'a, 'b>() -> App<'a, 'b> {
                 .takes_value(true)
                 .default_value("9077"),
         )
+        .arg(
+            Arg::with_name("cert")
+                .long("cert")
+                .value_name("PEM")
+                .help("Listens over TLS with this certificate (chain)")
+                .takes_value(true)
+                .requires("key"),
+        )
+        .arg(
+            Arg::with_name("key")
+                .long("key")
+                .value_name("PEM")
+                .help("Sets the private key for --cert")
+                .takes_value(true)
+                .requires("cert"),
+        )
+        .arg(
+            Arg::with_name("ca")
+                .long("ca")
+                .value_name("PEM")
+                .help("Requires clients to present a certificate signed by this CA")
+                .takes_value(true)
+                .requires("cert"),
+        )
+        .arg(
+            Arg::with_name("new-token")
+                .long("new-token")
+                .value_name("NAME")
+                .help("Creates an access token, prints it, and exits")
+                .takes_value(true)
+                .conflicts_with("revoke-token"),
+        )
+        .arg(
+            Arg::with_name("role")
+                .long("role")
+                .value_name("ROLE")
+                .help("Sets what a new token is allowed to do")
+                .takes_value(true)
+                .possible_values(&["target", "reader", "admin"])
+                .default_value("reader"),
+        )
+        .arg(
+            Arg::with_name("revoke-token")
+                .long("revoke-token")
+                .value_name("NAME")
+                .help("Revokes an access token and exits")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("v")
                 .short("v")
@@ -0,0 +1,83 @@
+use super::build::build_path;
+use log::{debug, info};
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::process::Command;
+
+/// Largest amount of artefact data sent in one message.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Path to the packed artefact of a built release.
+pub fn artefact_path(release_id: i32) -> PathBuf {
+    build_path(release_id).with_extension("tar")
+}
+
+fn checksum_path(release_id: i32) -> PathBuf {
+    build_path(release_id).with_extension("tar.sha256")
+}
+
+/// The checksum of a release's packed artefact.
+pub fn read_checksum(release_id: i32) -> Result<String> {
+    fs::read_to_string(checksum_path(release_id))
+}
+
+/// Packs a built release into an artefact, and records its checksum.
+///
+/// Both are written aside and moved in place, the checksum last, so a rebuild never serves a
+/// half-written artefact.
+pub fn pack(release_id: i32) -> Result<()> {
+    let dir = build_path(release_id);
+    let path = artefact_path(release_id);
+    let sum_path = checksum_path(release_id);
+    let next = path.with_extension("tar.next");
+    let next_sum = sum_path.with_extension("sha256.next");
+    info!("packing {:?} into {:?}", dir, path);
+
+    let status = Command::new("tar")
+        .arg("--create")
+        .arg("--exclude=.git")
+        .arg("--file")
+        .arg(&next)
+        .arg("--directory")
+        .arg(&dir)
+        .arg(".")
+        .status()?;
+
+    if !status.success() {
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!("failed to pack artefact: tar {}", status),
+        ));
+    }
+
+    let sum = crate::checksum(File::open(&next)?)?;
+    debug!("artefact for {} has checksum {}", release_id, sum);
+    fs::write(&next_sum, sum)?;
+
+    fs::rename(&next, &path)?;
+    fs::rename(&next_sum, &sum_path)
+}
+
+/// Reads one chunk of an artefact, along with its total size and checksum.
+pub fn read(release_id: i32, offset: u64) -> Result<(Vec<u8>, u64, String)> {
+    let mut file = File::open(artefact_path(release_id))?;
+    let size = file.metadata()?.len();
+    let sum = read_checksum(release_id)?;
+
+    if offset > size {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "offset {} is past the end of the artefact ({})",
+                offset, size
+            ),
+        ));
+    }
+
+    file.seek(SeekFrom::Start(offset))?;
+    let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+    file.take(CHUNK_SIZE as u64).read_to_end(&mut chunk)?;
+
+    Ok((chunk, size, sum))
+}
@@ -0,0 +1,159 @@
+//! Token authentication for castle connections.
+//!
+//! Clients present a pre-shared token in the websocket handshake, as an `Authorization: Bearer`
+//! header. Only a hash of each token is stored, along with the role it grants. Connections without
+//! a token are refused in the handshake. Looking the token up takes a trip to the database, so
+//! it's done once the connection is open, off the websocket thread: calls wait for it, and the
+//! connection is closed (with `api::UNAUTHORIZED`) if the token is refused.
+
+use super::{data, Missive};
+pub use crate::api::{ADMIN, ANY, READ, TARGET};
+use crate::db::{models::NewToken, models::Token, types::Role};
+use crate::rpc::app_error;
+use crate::Bus;
+use jsonrpc_core::Result as RpcResult;
+use log::warn;
+use serde_json::json;
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// How long calls wait on the connection's token to be checked.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What a connection authenticated with.
+///
+/// Set by the server once it's checked the token from the handshake, and checked by the RPC
+/// handlers.
+#[derive(Clone, Debug, Default)]
+pub struct Session {
+    token: Arc<RwLock<Option<Token>>>,
+    certificate: Arc<RwLock<Option<String>>>,
+
+    /// Whether the token has been checked yet, whichever way that went
+    checked: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl Session {
+    /// Records the token the connection authenticated with.
+    pub fn set(&self, token: Token) {
+        *self.token.write().expect("session lock poisoned") = Some(token);
+        self.settle();
+    }
+
+    /// Records that the connection's token was refused.
+    pub fn refuse(&self) {
+        self.settle();
+    }
+
+    fn settle(&self) {
+        let (checked, settled) = &*self.checked;
+        *checked.lock().expect("session lock poisoned") = true;
+        settled.notify_all();
+    }
+
+    /// The role the connection authenticated with, once its token is checked.
+    ///
+    /// This waits on the check, so don't call it from the websocket thread.
+    pub fn role(&self) -> Option<Role> {
+        let (checked, settled) = &*self.checked;
+        let deadline = Instant::now() + CHECK_TIMEOUT;
+        let mut done = checked.lock().expect("session lock poisoned");
+        while !*done {
+            let now = Instant::now();
+            if now >= deadline {
+                warn!("gave up waiting on the token check");
+                break;
+            }
+
+            done = settled
+                .wait_timeout(done, deadline - now)
+                .expect("session lock poisoned")
+                .0;
+        }
+        drop(done);
+
+        self.token
+            .read()
+            .expect("session lock poisoned")
+            .as_ref()
+            .map(|token| token.role)
+    }
+
+    /// Records the name on the client's verified TLS certificate.
+    pub fn set_certificate(&self, name: String) {
+        *self.certificate.write().expect("session lock poisoned") = Some(name);
+    }
+
+    /// The name on the client's verified TLS certificate, if it presented one.
+    pub fn certificate(&self) -> Option<String> {
+        self.certificate
+            .read()
+            .expect("session lock poisoned")
+            .clone()
+    }
+
+    /// Errors unless the connection authenticated with one of the given roles.
+    pub fn permit(&self, roles: &[Role]) -> RpcResult<()> {
+        match self.role() {
+            Some(ref role) if roles.contains(role) => Ok(()),
+            role => Err(app_error(
+                403,
+                "not permitted",
+                Some(json!({ "role": role, "allowed": roles })),
+            )),
+        }
+    }
+}
+
+/// The bearer token in a handshake request, if any.
+pub fn bearer(req: &ws::Request) -> Option<String> {
+    let header = String::from_utf8(req.header("authorization")?.clone()).ok()?;
+    let mut parts = header.splitn(2, ' ');
+    match (parts.next(), parts.next()) {
+        (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => {
+            Some(token.trim().into())
+        }
+        _ => None,
+    }
+}
+
+fn hash(token: &str) -> String {
+    crate::checksum(token.as_bytes()).expect("hashing from memory cannot fail")
+}
+
+/// Looks up a token presented in a handshake request.
+pub fn authenticate(bus: &Bus<Missive>, token: &str) -> RpcResult<Token> {
+    if let Missive::Token(token) =
+        data::request(bus, data::Topic::Authenticate { hash: hash(token) })?
+    {
+        Ok(token)
+    } else {
+        unreachable!()
+    }
+}
+
+/// Generates and stores a new token, and returns it.
+///
+/// This is the only time the token itself is available: only its hash is kept.
+pub fn create_token(bus: &Bus<Missive>, name: &str, role: Role) -> RpcResult<String> {
+    let token = format!("{}{}", Uuid::new_v4(), Uuid::new_v4()).replace('-', "");
+    data::request(
+        bus,
+        data::Topic::CreateToken {
+            token: NewToken {
+                name: name.into(),
+                hash: hash(&token),
+                role,
+            },
+        },
+    )?;
+
+    Ok(token)
+}
+
+/// Revokes the live token with a given name.
+pub fn revoke_token(bus: &Bus<Missive>, name: &str) -> RpcResult<()> {
+    data::request(bus, data::Topic::RevokeToken { name: name.into() })?;
+    Ok(())
+}
@@ -0,0 +1,198 @@
+use super::{artefact, data, git, Missive};
+use crate::db::{
+    models,
+    types::{LogStream, ReleaseState},
+};
+use crate::{Bus, Limit};
+use chrono::Utc;
+use log::{debug, error, info, trace, warn};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result};
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread::JoinHandle;
+
+/// Path to the working directory a release is built in.
+///
+/// This is kept after the build, as it's what gets shipped to targets.
+pub fn build_path(release_id: i32) -> PathBuf {
+    super::data_dir()
+        .join("builds")
+        .join(release_id.to_string())
+}
+
+pub fn build_service(bus: Bus<Missive>) {
+    // subscribe before anything can be published
+    let bus = bus.launch_with(Limit::blocking());
+    bus.subscribe(super::BUILDS_TOPIC);
+
+    std::thread::Builder::new()
+        .name("build service".into())
+        .spawn(move || {
+            debug!("build service thread start");
+
+            for missive in bus.iter() {
+                match missive {
+                    Missive::Build(release) => build(&bus, release),
+                    _ => continue,
+                }
+            }
+
+            debug!("build service thread end");
+        })
+        .expect("failed to start build service");
+}
+
+fn build(bus: &Bus<Missive>, release: models::Release) {
+    info!("building release {} ({})", release.tag, release.id);
+    let started = Utc::now();
+
+    update(
+        bus,
+        release.id,
+        models::ReleaseBuild {
+            state: ReleaseState::Building,
+            build_started: Some(started),
+            build_finished: None,
+            build_status: None,
+        },
+    );
+
+    let (state, status) = match run(bus, &release) {
+        Err(err) => {
+            error!("build of {} could not run: {}", release.tag, err);
+            (ReleaseState::Failed, None)
+        }
+        Ok(status) => {
+            if status.success() {
+                info!("build of {} succeeded", release.tag);
+                (ReleaseState::Ready, status.code())
+            } else {
+                warn!("build of {} failed: {}", release.tag, status);
+                (ReleaseState::Failed, status.code())
+            }
+        }
+    };
+
+    let finished = Utc::now();
+    debug!(
+        "build of {} took {}s",
+        release.tag,
+        finished.signed_duration_since(started).num_seconds()
+    );
+
+    update(
+        bus,
+        release.id,
+        models::ReleaseBuild {
+            state,
+            build_started: Some(started),
+            build_finished: Some(finished),
+            build_status: status,
+        },
+    );
+}
+
+fn run(bus: &Bus<Missive>, release: &models::Release) -> Result<ExitStatus> {
+    let app_id = release
+        .app_id
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "release has no app"))?;
+
+    let mirror = git::mirror_path(app_id);
+    if !mirror.exists() {
+        git::sync(bus, app_id, &release.repo)
+            .map_err(|err| Error::new(ErrorKind::Other, err.message))?;
+    }
+
+    let dir = build_path(release.id);
+    git::checkout(&mirror, &release.tag, &dir)?;
+
+    let script = &release.build_script;
+    let mut cmd = Command::new("sh");
+    if dir.join(script).is_file() {
+        cmd.arg(script);
+    } else {
+        cmd.arg("-c").arg(script);
+    }
+
+    debug!("running build script {:?} in {:?}", script, dir);
+    let mut child = cmd
+        .current_dir(&dir)
+        .env("TREBUCHET_RELEASE", &release.tag)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let readers = vec![
+        stream_lines(bus, release.id, LogStream::Stdout, child.stdout.take()),
+        stream_lines(bus, release.id, LogStream::Stderr, child.stderr.take()),
+    ];
+
+    let status = child.wait()?;
+    for reader in readers {
+        if reader.join().is_err() {
+            error!("build log reader for {} panicked", release.tag);
+        }
+    }
+
+    if status.success() {
+        artefact::pack(release.id)?;
+    }
+
+    Ok(status)
+}
+
+fn stream_lines<R: Read + Send + 'static>(
+    bus: &Bus<Missive>,
+    release_id: i32,
+    stream: LogStream,
+    pipe: Option<R>,
+) -> JoinHandle<()> {
+    let bus = bus.clone();
+    std::thread::Builder::new()
+        .name(format!("build log {} {:?}", release_id, stream))
+        .spawn(move || {
+            let pipe = if let Some(pipe) = pipe {
+                pipe
+            } else {
+                warn!("build {:?} for {} was not captured", stream, release_id);
+                return;
+            };
+
+            for line in BufReader::new(pipe).lines() {
+                let line = match line {
+                    Err(err) => {
+                        warn!("failed to read build {:?}: {}", stream, err);
+                        break;
+                    }
+                    Ok(line) => line,
+                };
+
+                trace!("build {} {:?}: {}", release_id, stream, line);
+                let log = models::NewBuildLog {
+                    release_id,
+                    stream: stream.clone(),
+                    line,
+                };
+
+                match data::request(&bus, data::Topic::AddBuildLog { log }) {
+                    Ok(Missive::BuildLog(log)) => {
+                        bus.publish(&super::release_topic(release_id), Missive::BuildOutput(log))
+                    }
+                    Ok(_) => unreachable!(),
+                    Err(err) => error!("failed to record build log: {:?}", err),
+                }
+            }
+        })
+        .expect("failed to start build log reader")
+}
+
+fn update(bus: &Bus<Missive>, id: i32, build: models::ReleaseBuild) {
+    match data::request(bus, data::Topic::UpdateBuild { id, build }) {
+        Ok(Missive::Release(release)) => {
+            bus.publish(&super::release_topic(id), Missive::BuildState(release))
+        }
+        Ok(_) => unreachable!(),
+        Err(err) => error!("failed to record build state of release {}: {:?}", id, err),
+    }
+}
@@ -1,13 +1,16 @@
 use super::Missive;
 use crate::client::Kind;
-use crate::db::{models, schema};
+use crate::db::{models, schema, types::ReleaseState};
 use crate::rpc::app_error;
-use crate::Bus;
-use crossbeam_channel::bounded;
+use crate::{Bus, Incoming, RequestError};
+use chrono::Utc;
 use diesel::prelude::*;
 use jsonrpc_core::{Error as RpcError, Result as RpcResult};
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 use regex::Regex;
+use serde_json::json;
+use std::time::Duration;
+use uuid::Uuid;
 
 fn log_only(res: QueryResult<usize>) {
     if let Err(err) = res {
@@ -15,6 +18,44 @@ fn log_only(res: QueryResult<usize>) {
     }
 }
 
+/// Tells interested connections about a client coming or going.
+fn announce(bus: &Bus<Missive>, res: QueryResult<Option<models::Client>>) {
+    match res {
+        Ok(Some(client)) => bus.publish(super::CLIENTS_TOPIC, Missive::Presence(client)),
+        Ok(None) => {}
+        Err(err) => error!("did not save! {:?}", err),
+    }
+}
+
+/// Which clients to list or watch.
+#[derive(Clone, Debug, Default)]
+pub struct ClientFilter {
+    pub kind: Option<Kind>,
+    pub name: Option<Regex>,
+    pub tags: Vec<String>,
+    pub connected: Option<bool>,
+}
+
+impl ClientFilter {
+    /// Whether a client matches on kind, name, and tags (any of).
+    ///
+    /// The connected state isn't considered here, so watchers see clients both come and go.
+    pub fn matches(&self, client: &models::Client) -> bool {
+        self.kind
+            .as_ref()
+            .map_or(true, |kind| client.target == (*kind == Kind::Target))
+            && self
+                .name
+                .as_ref()
+                .map_or(true, |re| re.is_match(&client.name))
+            && (self.tags.is_empty()
+                || client
+                    .tags
+                    .as_ref()
+                    .map_or(false, |ts| ts.iter().any(|t| self.tags.contains(t))))
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum Topic {
     AppList {
@@ -25,14 +66,82 @@ pub enum Topic {
         repo: String,
         build_script: Option<String>,
     },
+    GetApp {
+        name: String,
+    },
+    ArchiveApp {
+        name: String,
+        force: bool,
+    },
+    UpdateApp {
+        name: String,
+        new_name: Option<String>,
+        repo: Option<String>,
+        build_script: Option<String>,
+    },
+    SyncReleases {
+        app: models::App,
+        tags: Vec<String>,
+    },
+    ReleaseList {
+        app: String,
+        filter: Option<Regex>,
+        status: Option<ReleaseState>,
+    },
+    GetRelease {
+        app: String,
+        tag: String,
+    },
+    QueueBuild {
+        app: String,
+        tag: String,
+        rebuild: bool,
+    },
+    UpdateBuild {
+        id: i32,
+        build: models::ReleaseBuild,
+    },
+    TargetList {
+        names: Vec<String>,
+        tags: Vec<String>,
+    },
+    AddBuildLog {
+        log: models::NewBuildLog,
+    },
+    BuildLogs {
+        release_id: i32,
+    },
+    ClientList {
+        filter: ClientFilter,
+    },
+    RollbackPlan {
+        app: String,
+        to: Option<String>,
+        names: Vec<String>,
+        tags: Vec<String>,
+    },
+    Authenticate {
+        hash: String,
+    },
+    CreateToken {
+        token: models::NewToken,
+    },
+    RevokeToken {
+        name: String,
+    },
 }
 
+/// How long to wait on the data service before giving up.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
 pub fn request(bus: &Bus<Missive>, topic: Topic) -> RpcResult<Missive> {
-    let (tx, rx) = bounded(1);
     trace!("making {:?} request to data service", topic);
-    bus.send_top(Missive::DataRequest { topic, tx });
-    rx.recv()
-        .map_err(|_| app_error(68, "data service channel disconnect", None))?
+    match bus.request_top(Missive::DataRequest(topic), TIMEOUT) {
+        Ok(Missive::Error(err)) => Err(err),
+        Ok(missive) => Ok(missive),
+        Err(RequestError::Gone) => Err(app_error(68, "data service channel disconnect", None)),
+        Err(RequestError::Timeout) => Err(app_error(408, "data service timed out", None)),
+    }
 }
 
 pub fn data_service(bus: Bus<Missive>) {
@@ -41,20 +150,62 @@ pub fn data_service(bus: Bus<Missive>) {
         .spawn(move || {
             debug!("data service thread start");
             let db = crate::db::connect();
+            let boot_id = Uuid::new_v4();
+            info!("castle boot {}", boot_id);
+
+            {
+                info!("disconnecting clients left connected by a previous run");
+                use schema::clients::dsl::*;
+                log_only(
+                    diesel::update(clients.filter(connected.eq(true)))
+                        .set((connected.eq(false), boot.eq(boot_id)))
+                        .execute(&db),
+                )
+            }
+
+            {
+                info!("failing builds interrupted by a previous shutdown");
+                use schema::releases::dsl::*;
+                log_only(
+                    diesel::update(releases.filter(state.eq(ReleaseState::Building)))
+                        .set(state.eq(ReleaseState::Failed))
+                        .execute(&db),
+                )
+            }
 
-            for (source, missive) in bus.iter_with_source() {
+            {
+                info!("unqueueing builds lost in a previous shutdown");
+                use schema::releases::dsl::*;
+                log_only(
+                    diesel::update(releases.filter(state.eq(ReleaseState::Queued)))
+                        .set(state.eq(ReleaseState::Todo))
+                        .execute(&db),
+                )
+            }
+
+            for Incoming {
+                source,
+                content: missive,
+                request,
+            } in bus.incoming()
+            {
                 match missive {
                     Missive::Hello {
+                        identity,
                         app,
                         kind,
                         name,
                         tags,
                     } => {
-                        info!("received hello from {}", source);
+                        info!("received hello from {} ({})", source, identity);
                         use schema::clients;
 
+                        // a client coming back takes over its row with its new connection
                         let cli = models::NewClient {
+                            identity,
                             connection: source.clone(),
+                            connected: true,
+                            boot: boot_id,
                             app,
                             target: match kind {
                                 Kind::Target => true,
@@ -64,26 +215,49 @@ pub fn data_service(bus: Bus<Missive>) {
                             tags,
                         };
 
-                        log_only(
+                        announce(
+                            &bus,
                             diesel::insert_into(clients::table)
                                 .values(&cli)
-                                .on_conflict(clients::columns::connection)
+                                .on_conflict(clients::columns::identity)
                                 .do_update()
                                 .set(&cli)
-                                .execute(&db),
+                                .get_result(&db)
+                                .map(Some),
                         )
                     }
+                    Missive::Deployed {
+                        target,
+                        release,
+                        error,
+                    } => {
+                        info!("recording deploy of release {} on {}", release, target);
+                        log_only(record_deployment(&db, target, release, error))
+                    }
                     Missive::Exit => {
                         info!("recording client exit {}", source);
                         use schema::clients;
-                        log_only(
+                        announce(
+                            &bus,
                             diesel::update(clients::table)
                                 .filter(clients::columns::connection.eq(source))
-                                .set(clients::columns::connected.eq(false))
-                                .execute(&db),
+                                .set((
+                                    clients::columns::connected.eq(false),
+                                    clients::columns::boot.eq(boot_id),
+                                ))
+                                .get_result(&db)
+                                .optional(),
                         )
                     }
-                    Missive::DataRequest { topic, tx } => {
+                    Missive::DataRequest(topic) => {
+                        let request = match request {
+                            Some(request) => request,
+                            None => {
+                                warn!("got a {:?} request with nowhere to reply", topic);
+                                continue;
+                            }
+                        };
+
                         info!("received {:?} request from {}", topic, source);
                         let data = match topic {
                             Topic::AppList { filter } => app_list(&db, filter),
@@ -92,11 +266,41 @@ pub fn data_service(bus: Bus<Missive>) {
                                 repo,
                                 build_script,
                             } => create_app(&db, name, repo, build_script),
+                            Topic::GetApp { name } => get_app(&db, name),
+                            Topic::ArchiveApp { name, force } => archive_app(&db, name, force),
+                            Topic::UpdateApp {
+                                name,
+                                new_name,
+                                repo,
+                                build_script,
+                            } => update_app(&db, name, new_name, repo, build_script),
+                            Topic::SyncReleases { app, tags } => sync_releases(&db, app, tags),
+                            Topic::ReleaseList {
+                                app,
+                                filter,
+                                status,
+                            } => release_list(&db, app, filter, status),
+                            Topic::GetRelease { app, tag } => get_release(&db, app, tag),
+                            Topic::QueueBuild { app, tag, rebuild } => {
+                                queue_build(&db, app, tag, rebuild)
+                            }
+                            Topic::UpdateBuild { id, build } => update_build(&db, id, build),
+                            Topic::TargetList { names, tags } => target_list(&db, names, tags),
+                            Topic::AddBuildLog { log } => add_build_log(&db, log),
+                            Topic::BuildLogs { release_id } => build_logs(&db, release_id),
+                            Topic::ClientList { filter } => client_list(&db, filter),
+                            Topic::RollbackPlan {
+                                app,
+                                to,
+                                names,
+                                tags,
+                            } => rollback_plan(&db, app, to, names, tags),
+                            Topic::Authenticate { hash } => authenticate(&db, hash),
+                            Topic::CreateToken { token } => create_token(&db, token),
+                            Topic::RevokeToken { name } => revoke_token(&db, name),
                         };
 
-                        if let Err(err) = tx.send(data) {
-                            error!("failed to send data back to {}: {:?}", source, err);
-                        }
+                        bus.reply(request, data.unwrap_or_else(Missive::Error));
                     }
                     _ => continue,
                 }
@@ -108,8 +312,17 @@ pub fn data_service(bus: Bus<Missive>) {
 }
 
 fn db_error(err: diesel::result::Error) -> RpcError {
-    use serde_json::json;
+    use diesel::result::{DatabaseErrorKind, Error};
     match err {
+        Error::NotFound => app_error(404, "not found", None),
+        Error::DatabaseError(DatabaseErrorKind::UniqueViolation, ref info) => app_error(
+            409,
+            "conflicts with an existing record",
+            Some(json!({
+                "constraint": info.constraint_name(),
+                "details": info.details(),
+            })),
+        ),
         _ => app_error(
             800,
             "uncaught database error",
@@ -121,7 +334,10 @@ fn db_error(err: diesel::result::Error) -> RpcError {
 fn app_list(db: &PgConnection, filter: Option<Regex>) -> RpcResult<Missive> {
     use schema::apps::dsl::*;
 
-    let mut results = apps.load::<models::App>(db).map_err(db_error)?;
+    let mut results = apps
+        .filter(archived.is_null())
+        .load::<models::App>(db)
+        .map_err(db_error)?;
 
     if let Some(re) = filter {
         results = results
@@ -153,3 +369,548 @@ fn create_app(
             .map_err(db_error)?
     }))
 }
+
+fn get_app(db: &PgConnection, app_name: String) -> RpcResult<Missive> {
+    use schema::apps::dsl::*;
+
+    apps.filter(name.eq(&app_name))
+        .filter(archived.is_null())
+        .first::<models::App>(db)
+        .optional()
+        .map_err(db_error)?
+        .map(Missive::App)
+        .ok_or_else(|| app_error(404, "app not found", Some(json!(app_name))))
+}
+
+fn update_app(
+    db: &PgConnection,
+    app_name: String,
+    new_name: Option<String>,
+    new_repo: Option<String>,
+    new_build_script: Option<String>,
+) -> RpcResult<Missive> {
+    let app = if let Missive::App(app) = get_app(db, app_name)? {
+        app
+    } else {
+        unreachable!()
+    };
+
+    let changes = models::NewApp {
+        name: new_name.unwrap_or(app.name),
+        repo: new_repo.unwrap_or(app.repo),
+        build_script: new_build_script,
+    };
+
+    Ok(Missive::App({
+        use schema::apps::dsl::*;
+        diesel::update(apps.find(app.id))
+            .set(&changes)
+            .get_result(db)
+            .map_err(db_error)?
+    }))
+}
+
+fn archive_app(db: &PgConnection, app_name: String, force: bool) -> RpcResult<Missive> {
+    use schema::{apps, deployments, releases};
+
+    let app = if let Missive::App(app) = get_app(db, app_name)? {
+        app
+    } else {
+        unreachable!()
+    };
+
+    let live_releases = releases::table
+        .filter(releases::app_id.eq(app.id))
+        .filter(releases::archived.is_null());
+
+    let count: i64 = live_releases
+        .clone()
+        .count()
+        .get_result(db)
+        .map_err(db_error)?;
+    if count > 0 && !force {
+        return Err(app_error(
+            409,
+            "app has releases, force to archive them as well",
+            Some(json!({ "app": app.name, "releases": count })),
+        ));
+    }
+
+    let active: i64 = deployments::table
+        .inner_join(releases::table)
+        .filter(releases::app_id.eq(app.id))
+        .filter(deployments::active.eq(true))
+        .count()
+        .get_result(db)
+        .map_err(db_error)?;
+    if active > 0 && !force {
+        return Err(app_error(
+            409,
+            "app is deployed on targets, force to archive it anyway",
+            Some(json!({ "app": app.name, "deployments": active })),
+        ));
+    }
+
+    let now = Utc::now();
+    db.transaction(|| {
+        diesel::update(live_releases)
+            .set(releases::archived.eq(now))
+            .execute(db)?;
+
+        diesel::update(apps::table.find(app.id))
+            .set(apps::archived.eq(now))
+            .get_result(db)
+    })
+    .map(Missive::App)
+    .map_err(db_error)
+}
+
+fn sync_releases(db: &PgConnection, app: models::App, tags: Vec<String>) -> RpcResult<Missive> {
+    if tags.is_empty() {
+        return Ok(Missive::ReleaseList(Vec::new()));
+    }
+
+    let new_releases: Vec<models::NewRelease> = tags
+        .into_iter()
+        .map(|tag| models::NewRelease {
+            app_id: app.id,
+            tag,
+            repo: app.repo.clone(),
+            build_script: app.build_script.clone(),
+        })
+        .collect();
+
+    Ok(Missive::ReleaseList({
+        use schema::releases::dsl::*;
+        diesel::insert_into(releases)
+            .values(&new_releases)
+            .on_conflict((app_id, tag))
+            .do_nothing()
+            .get_results(db)
+            .map_err(db_error)?
+    }))
+}
+
+fn release_list(
+    db: &PgConnection,
+    app_name: String,
+    filter: Option<Regex>,
+    status: Option<ReleaseState>,
+) -> RpcResult<Missive> {
+    let app = if let Missive::App(app) = get_app(db, app_name)? {
+        app
+    } else {
+        unreachable!()
+    };
+
+    use schema::releases::dsl::*;
+    let mut query = releases
+        .filter(app_id.eq(app.id))
+        .filter(archived.is_null())
+        .order(created.asc())
+        .into_boxed();
+
+    if let Some(status) = status {
+        query = query.filter(state.eq(status));
+    }
+
+    let mut results = query.load::<models::Release>(db).map_err(db_error)?;
+
+    if let Some(re) = filter {
+        results.retain(|release| re.is_match(&release.tag));
+    }
+
+    Ok(Missive::ReleaseList(results))
+}
+
+fn get_release(db: &PgConnection, app_name: String, release_tag: String) -> RpcResult<Missive> {
+    use schema::{apps, releases};
+
+    releases::table
+        .inner_join(apps::table)
+        .filter(apps::name.eq(&app_name))
+        .filter(apps::archived.is_null())
+        .filter(releases::tag.eq(&release_tag))
+        .filter(releases::archived.is_null())
+        .select(releases::all_columns)
+        .first::<models::Release>(db)
+        .optional()
+        .map_err(db_error)?
+        .map(Missive::Release)
+        .ok_or_else(|| {
+            app_error(
+                404,
+                "release not found",
+                Some(json!({ "app": app_name, "tag": release_tag })),
+            )
+        })
+}
+
+/// Marks a release as queued for building, unless it's already queued, building, or built.
+///
+/// Checked and done here, on the one data service thread, so a release can't be queued twice.
+fn queue_build(
+    db: &PgConnection,
+    app_name: String,
+    release_tag: String,
+    rebuild: bool,
+) -> RpcResult<Missive> {
+    let release = if let Missive::Release(release) = get_release(db, app_name, release_tag)? {
+        release
+    } else {
+        unreachable!()
+    };
+
+    let refusal = match release.state {
+        ReleaseState::Queued => Some("release is already queued"),
+        ReleaseState::Building => Some("release is already building"),
+        ReleaseState::Ready if !rebuild => Some("release is already built, rebuild it instead"),
+        _ => None,
+    };
+
+    if let Some(message) = refusal {
+        return Err(app_error(409, message, Some(json!(release.tag))));
+    }
+
+    update_build(
+        db,
+        release.id,
+        models::ReleaseBuild {
+            state: ReleaseState::Queued,
+            build_started: None,
+            build_finished: None,
+            build_status: None,
+        },
+    )
+}
+
+fn update_build(
+    db: &PgConnection,
+    release_id: i32,
+    build: models::ReleaseBuild,
+) -> RpcResult<Missive> {
+    use schema::{build_logs, releases};
+
+    if let ReleaseState::Queued | ReleaseState::Building = build.state {
+        // a fresh build starts with a fresh log
+        diesel::delete(build_logs::table.filter(build_logs::release_id.eq(release_id)))
+            .execute(db)
+            .map_err(db_error)?;
+    }
+
+    Ok(Missive::Release(
+        diesel::update(releases::table.find(release_id))
+            .set(&build)
+            .get_result(db)
+            .map_err(db_error)?,
+    ))
+}
+
+fn client_list(db: &PgConnection, filter: ClientFilter) -> RpcResult<Missive> {
+    use schema::clients::dsl::*;
+
+    let mut query = clients.order(name.asc()).into_boxed();
+    if let Some(state) = filter.connected {
+        query = query.filter(connected.eq(state));
+    }
+
+    let mut results = query.load::<models::Client>(db).map_err(db_error)?;
+    results.retain(|client| filter.matches(client));
+
+    Ok(Missive::ClientList(results))
+}
+
+/// Connected targets selected by name or by any of their tags.
+fn target_list(
+    db: &PgConnection,
+    names: Vec<String>,
+    with_tags: Vec<String>,
+) -> RpcResult<Missive> {
+    use schema::clients::dsl::*;
+
+    let mut results = clients
+        .filter(target.eq(true))
+        .filter(connected.eq(true))
+        .order(name.asc())
+        .load::<models::Client>(db)
+        .map_err(db_error)?;
+
+    results.retain(|client| {
+        names.contains(&client.name)
+            || client
+                .tags
+                .as_ref()
+                .map_or(false, |ts| ts.iter().any(|t| with_tags.contains(t)))
+    });
+
+    Ok(Missive::ClientList(results))
+}
+
+fn add_build_log(db: &PgConnection, log: models::NewBuildLog) -> RpcResult<Missive> {
+    use schema::build_logs::dsl::*;
+
+    Ok(Missive::BuildLog(
+        diesel::insert_into(build_logs)
+            .values(&log)
+            .get_result(db)
+            .map_err(db_error)?,
+    ))
+}
+
+fn build_logs(db: &PgConnection, release: i32) -> RpcResult<Missive> {
+    use schema::build_logs::dsl::*;
+
+    Ok(Missive::BuildLogs(
+        build_logs
+            .filter(release_id.eq(release))
+            .order(id.asc())
+            .load(db)
+            .map_err(db_error)?,
+    ))
+}
+
+/// Records the outcome of a deploy to the target on a connection.
+///
+/// A successful deploy becomes the active one for that app on targets of that name, as names are
+/// what persist across target reconnects.
+fn record_deployment(
+    db: &PgConnection,
+    connection_id: Uuid,
+    release: i32,
+    error: Option<String>,
+) -> QueryResult<usize> {
+    use schema::{clients, deployments, releases};
+
+    db.transaction(|| {
+        let client = clients::table
+            .filter(clients::connection.eq(connection_id))
+            .first::<models::Client>(db)?;
+
+        let active = error.is_none();
+        if active {
+            let app_id: Option<i32> = releases::table
+                .find(release)
+                .select(releases::app_id)
+                .first(db)?;
+
+            let same_app = releases::table
+                .filter(releases::app_id.eq(app_id))
+                .select(releases::id);
+            let same_target = clients::table
+                .filter(clients::name.eq(&client.name))
+                .select(clients::id);
+
+            diesel::update(
+                deployments::table
+                    .filter(deployments::release_id.eq_any(same_app))
+                    .filter(deployments::client_id.eq_any(same_target))
+                    .filter(deployments::active.eq(true)),
+            )
+            .set(deployments::active.eq(false))
+            .execute(db)?;
+        }
+
+        diesel::insert_into(deployments::table)
+            .values(&models::NewDeployment {
+                client_id: client.id,
+                release_id: release,
+                active,
+                error,
+            })
+            .execute(db)
+    })
+}
+
+/// Works out which release each selected target should roll back to.
+///
+/// Without an explicit release, that's the last release successfully deployed to the target
+/// before its current one. Targets with nothing to roll back to are left out.
+fn rollback_plan(
+    db: &PgConnection,
+    app_name: String,
+    to: Option<String>,
+    names: Vec<String>,
+    tags: Vec<String>,
+) -> RpcResult<Missive> {
+    use schema::{clients, deployments, releases};
+
+    let app = if let Missive::App(app) = get_app(db, app_name.clone())? {
+        app
+    } else {
+        unreachable!()
+    };
+
+    let explicit = match to {
+        Some(tag) => {
+            if let Missive::Release(release) = get_release(db, app_name, tag)? {
+                Some(release)
+            } else {
+                unreachable!()
+            }
+        }
+        None => None,
+    };
+
+    let targets = if names.is_empty() && tags.is_empty() {
+        clients::table
+            .filter(clients::target.eq(true))
+            .filter(clients::connected.eq(true))
+            .order(clients::name.asc())
+            .load::<models::Client>(db)
+            .map_err(db_error)?
+    } else if let Missive::ClientList(list) = target_list(db, names, tags)? {
+        list
+    } else {
+        unreachable!()
+    };
+
+    let mut plan = Vec::with_capacity(targets.len());
+    for target in targets {
+        let history = deployments::table
+            .inner_join(clients::table)
+            .inner_join(releases::table)
+            .filter(clients::name.eq(&target.name))
+            .filter(releases::app_id.eq(app.id))
+            .filter(deployments::error.is_null())
+            .order(deployments::id.desc())
+            .select(releases::all_columns)
+            .load::<models::Release>(db)
+            .map_err(db_error)?;
+
+        let current = match history.first() {
+            Some(release) => release.id,
+            None => {
+                debug!("{} has never had {} deployed", target.name, app.name);
+                continue;
+            }
+        };
+
+        let release = match explicit {
+            Some(ref release) if release.id == current => {
+                debug!("{} is already on {} {}", target.name, app.name, release.tag);
+                continue;
+            }
+            Some(ref release) => release.clone(),
+            None => match history.into_iter().find(|release| release.id != current) {
+                Some(release) => release,
+                None => {
+                    debug!("{} has no previous release of {}", target.name, app.name);
+                    continue;
+                }
+            },
+        };
+
+        plan.push((target, release));
+    }
+
+    Ok(Missive::RollbackPlan(plan))
+}
+
+/// The live token with a given hash.
+fn authenticate(db: &PgConnection, token_hash: String) -> RpcResult<Missive> {
+    use schema::tokens::dsl::*;
+
+    tokens
+        .filter(hash.eq(token_hash))
+        .filter(revoked.is_null())
+        .first::<models::Token>(db)
+        .optional()
+        .map_err(db_error)?
+        .map(Missive::Token)
+        .ok_or_else(|| app_error(401, "invalid token", None))
+}
+
+fn create_token(db: &PgConnection, token: models::NewToken) -> RpcResult<Missive> {
+    use schema::tokens::dsl::*;
+
+    Ok(Missive::Token(
+        diesel::insert_into(tokens)
+            .values(&token)
+            .get_result(db)
+            .map_err(db_error)?,
+    ))
+}
+
+fn revoke_token(db: &PgConnection, token_name: String) -> RpcResult<Missive> {
+    use schema::tokens::dsl::*;
+
+    diesel::update(
+        tokens
+            .filter(name.eq(&token_name))
+            .filter(revoked.is_null()),
+    )
+    .set(revoked.eq(Utc::now()))
+    .get_result::<models::Token>(db)
+    .optional()
+    .map_err(db_error)?
+    .map(Missive::Token)
+    .ok_or_else(|| app_error(404, "token not found", Some(json!(token_name))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted_tags(missive: Missive) -> Vec<String> {
+        if let Missive::ReleaseList(list) = missive {
+            let mut tags: Vec<String> = list.into_iter().map(|release| release.tag).collect();
+            tags.sort();
+            tags
+        } else {
+            unreachable!()
+        }
+    }
+
+    // needs a migrated database at DATABASE_URL, run with `cargo test -- --ignored`
+    #[test]
+    #[ignore]
+    fn sync_inserts_missing_tags() {
+        dotenv::dotenv().ok();
+        let db = crate::db::connect();
+        db.test_transaction::<_, (), _>(|| {
+            let app = if let Missive::App(app) = create_app(
+                &db,
+                "sync-test".into(),
+                "/srv/git/sync-test.git".into(),
+                Some("make".into()),
+            )
+            .unwrap()
+            {
+                app
+            } else {
+                unreachable!()
+            };
+
+            let none = sync_releases(&db, app.clone(), Vec::new()).unwrap();
+            assert!(sorted_tags(none).is_empty());
+
+            let first = sync_releases(&db, app.clone(), vec!["v1".into(), "v2".into()]).unwrap();
+            assert_eq!(sorted_tags(first), vec!["v1", "v2"]);
+
+            // only what's new is inserted, and returned
+            let second = sync_releases(
+                &db,
+                app.clone(),
+                vec!["v1".into(), "v2".into(), "v3".into()],
+            )
+            .unwrap();
+            assert_eq!(sorted_tags(second), vec!["v3"]);
+
+            if let Missive::ReleaseList(list) =
+                release_list(&db, app.name.clone(), None, None).unwrap()
+            {
+                assert_eq!(list.len(), 3);
+                for release in list {
+                    assert_eq!(release.app_id, Some(app.id));
+                    assert_eq!(release.state, ReleaseState::Todo);
+                    assert_eq!(release.repo, app.repo);
+                    assert_eq!(release.build_script, app.build_script);
+                }
+            } else {
+                unreachable!()
+            }
+
+            Ok(())
+        });
+    }
+}
@@ -0,0 +1,334 @@
+use super::Missive;
+use crate::rpc::app_error;
+use crate::{Bus, Incoming, Limit, RequestError};
+use jsonrpc_core::Result as RpcResult;
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use serde_json::json;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+use uuid::Uuid;
+
+lazy_static! {
+    /// Bus id of the git service.
+    static ref SERVICE: Uuid = Uuid::new_v4();
+}
+
+/// How long to wait on a clone or fetch before giving up.
+const TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Runs mirror clones and fetches one at a time, off the threads asking for them.
+pub fn git_service(bus: Bus<Missive>) {
+    let bus = bus.launch_as(*SERVICE, Limit::blocking());
+
+    std::thread::Builder::new()
+        .name("git service".into())
+        .spawn(move || {
+            debug!("git service thread start");
+
+            for Incoming {
+                content, request, ..
+            } in bus.incoming()
+            {
+                match (content, request) {
+                    (Missive::Mirror { app_id, repo }, Some(request)) => {
+                        let reply = match mirror(app_id, &repo).and_then(|path| tags(&path)) {
+                            Ok(tags) => Missive::Tags(tags),
+                            Err(err) => Missive::Error(app_error(
+                                500,
+                                "failed to sync from source repo",
+                                Some(json!(err.to_string())),
+                            )),
+                        };
+
+                        bus.reply(request, reply);
+                    }
+                    (Missive::Mirror { .. }, None) => {
+                        warn!("got a mirror request with nowhere to reply");
+                    }
+                    _ => continue,
+                }
+            }
+
+            debug!("git service thread end");
+        })
+        .expect("failed to start git service");
+}
+
+/// Has the git service clone or fetch an app's repo into its mirror, and returns its tags.
+pub fn sync(bus: &Bus<Missive>, app_id: i32, repo: &str) -> RpcResult<Vec<String>> {
+    let missive = Missive::Mirror {
+        app_id,
+        repo: repo.into(),
+    };
+
+    match bus.request(&SERVICE, missive, TIMEOUT) {
+        Ok(Missive::Tags(tags)) => Ok(tags),
+        Ok(Missive::Error(err)) => Err(err),
+        Ok(_) => unreachable!(),
+        Err(RequestError::Gone) => Err(app_error(68, "git service channel disconnect", None)),
+        Err(RequestError::Timeout) => Err(app_error(408, "git service timed out", None)),
+    }
+}
+
+/// Expands the `github:user/repo` shorthand into a full clone URL.
+///
+/// Anything else is assumed to already be something git understands.
+pub fn expand_repo(repo: &str) -> String {
+    if repo.starts_with("github:") {
+        format!("https://github.com/{}.git", &repo[7..])
+    } else {
+        repo.into()
+    }
+}
+
+/// Path to the local mirror of an app's repo.
+///
+/// Mirrors are keyed by app id rather than name so renames don't orphan them.
+pub fn mirror_path(app_id: i32) -> PathBuf {
+    super::data_dir()
+        .join("mirrors")
+        .join(format!("{}.git", app_id))
+}
+
+/// Clones or fetches the app's repo into its local mirror.
+///
+/// Use `sync` instead, so the git service does this one mirror at a time.
+fn mirror(app_id: i32, repo: &str) -> Result<PathBuf> {
+    let path = mirror_path(app_id);
+    mirror_at(&path, repo)?;
+    Ok(path)
+}
+
+/// Clones or fetches a repo into a mirror at the given path.
+fn mirror_at(path: &Path, repo: &str) -> Result<()> {
+    let url = expand_repo(repo);
+
+    if path.join("HEAD").exists() {
+        info!("fetching {} into existing mirror {:?}", url, path);
+        git(
+            Some(path),
+            &[
+                OsStr::new("remote"),
+                "set-url".as_ref(),
+                "origin".as_ref(),
+                url.as_ref(),
+            ],
+        )?;
+        git(
+            Some(path),
+            &[OsStr::new("remote"), "update".as_ref(), "--prune".as_ref()],
+        )?;
+    } else {
+        info!("cloning {} into new mirror {:?}", url, path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        git(
+            None,
+            &[
+                OsStr::new("clone"),
+                "--mirror".as_ref(),
+                url.as_ref(),
+                path.as_ref(),
+            ],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Lists all tags in a mirror.
+pub fn tags(mirror: &Path) -> Result<Vec<String>> {
+    Ok(git(Some(mirror), &[OsStr::new("tag"), "--list".as_ref()])?
+        .lines()
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// Checks out a tag from a mirror into a fresh working directory.
+pub fn checkout(mirror: &Path, tag: &str, dest: &Path) -> Result<()> {
+    if dest.exists() {
+        debug!("clearing previous checkout at {:?}", dest);
+        fs::remove_dir_all(dest)?;
+    }
+
+    git(
+        None,
+        &[
+            OsStr::new("clone"),
+            "--quiet".as_ref(),
+            "--branch".as_ref(),
+            tag.as_ref(),
+            mirror.as_ref(),
+            dest.as_ref(),
+        ],
+    )
+    .map(|_| ())
+}
+
+fn git(dir: Option<&Path>, args: &[&OsStr]) -> Result<String> {
+    let mut cmd = Command::new("git");
+    if let Some(dir) = dir {
+        cmd.current_dir(dir);
+    }
+
+    debug!("running git {:?} in {:?}", args, dir);
+    let out = cmd.args(args).output()?;
+
+    if out.status.success() {
+        Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+    } else {
+        Err(Error::new(
+            ErrorKind::Other,
+            format!(
+                "git {:?} failed ({}): {}",
+                args.get(0).unwrap_or(&OsStr::new("")),
+                out.status,
+                String::from_utf8_lossy(&out.stderr).trim()
+            ),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn run(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .current_dir(dir)
+            .args(&["-c", "user.name=Test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .status()
+            .expect("failed to run git");
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    /// A bare repo with a commit tagged with each of the given tags, and a clone to add more from.
+    fn source(tmp: &TempDir, tags: &[&str]) -> (PathBuf, PathBuf) {
+        let bare = tmp.path().join("source.git");
+        let work = tmp.path().join("work");
+        fs::create_dir_all(&bare).unwrap();
+        fs::create_dir_all(&work).unwrap();
+
+        run(&bare, &["init", "--quiet", "--bare"]);
+        run(&work, &["init", "--quiet"]);
+        run(
+            &work,
+            &["commit", "--quiet", "--allow-empty", "-m", "first"],
+        );
+        for tag in tags {
+            run(&work, &["tag", tag]);
+        }
+
+        push(&work, &bare);
+        (bare, work)
+    }
+
+    fn push(work: &Path, bare: &Path) {
+        run(
+            work,
+            &[
+                "push",
+                "--quiet",
+                "--tags",
+                bare.to_str().unwrap(),
+                "HEAD:master",
+            ],
+        );
+    }
+
+    #[test]
+    fn expands_github_shorthand() {
+        assert_eq!(
+            expand_repo("github:passcod/trebuchet"),
+            "https://github.com/passcod/trebuchet.git"
+        );
+    }
+
+    #[test]
+    fn leaves_other_repos_alone() {
+        for repo in &[
+            "https://example.com/repo.git",
+            "git@github.com:passcod/trebuchet.git",
+            "/srv/git/repo.git",
+        ] {
+            assert_eq!(&expand_repo(repo), repo);
+        }
+    }
+
+    #[test]
+    fn mirrors_a_new_repo() {
+        let tmp = TempDir::new().unwrap();
+        let (bare, _) = source(&tmp, &["v1.0.0", "v1.1.0"]);
+        let mirror = tmp.path().join("mirrors").join("1.git");
+
+        mirror_at(&mirror, bare.to_str().unwrap()).unwrap();
+        assert!(mirror.join("HEAD").exists());
+
+        let mut found = tags(&mirror).unwrap();
+        found.sort();
+        assert_eq!(found, vec!["v1.0.0", "v1.1.0"]);
+    }
+
+    #[test]
+    fn fetches_into_an_existing_mirror() {
+        let tmp = TempDir::new().unwrap();
+        let (bare, work) = source(&tmp, &["v1.0.0"]);
+        let mirror = tmp.path().join("mirror.git");
+
+        mirror_at(&mirror, bare.to_str().unwrap()).unwrap();
+        assert_eq!(tags(&mirror).unwrap(), vec!["v1.0.0"]);
+
+        run(
+            &work,
+            &["commit", "--quiet", "--allow-empty", "-m", "second"],
+        );
+        run(&work, &["tag", "v2.0.0"]);
+        push(&work, &bare);
+
+        mirror_at(&mirror, bare.to_str().unwrap()).unwrap();
+        let mut found = tags(&mirror).unwrap();
+        found.sort();
+        assert_eq!(found, vec!["v1.0.0", "v2.0.0"]);
+    }
+
+    #[test]
+    fn fails_on_a_missing_repo() {
+        let tmp = TempDir::new().unwrap();
+        let missing = tmp.path().join("nowhere.git");
+        let mirror = tmp.path().join("mirror.git");
+
+        assert!(mirror_at(&mirror, missing.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn lists_no_tags() {
+        let tmp = TempDir::new().unwrap();
+        let (bare, _) = source(&tmp, &[]);
+        let mirror = tmp.path().join("mirror.git");
+
+        mirror_at(&mirror, bare.to_str().unwrap()).unwrap();
+        assert!(tags(&mirror).unwrap().is_empty());
+    }
+
+    #[test]
+    fn checks_out_a_tag() {
+        let tmp = TempDir::new().unwrap();
+        let (bare, _) = source(&tmp, &["v1.0.0"]);
+        let mirror = tmp.path().join("mirror.git");
+        let dest = tmp.path().join("checkout");
+
+        mirror_at(&mirror, bare.to_str().unwrap()).unwrap();
+        checkout(&mirror, "v1.0.0", &dest).unwrap();
+        assert!(dest.join(".git").exists());
+    }
+}
@@ -1,20 +1,28 @@
 use crate::bus::{central, Bus};
 use clap::ArgMatches;
 use log::info;
+use openssl::ssl::SslAcceptor;
+use std::env;
+use std::path::{Path, PathBuf};
 use std::thread::JoinHandle;
 
 mod args;
+mod artefact;
+mod auth;
+mod build;
 mod data;
+mod git;
 mod rpc;
 mod server;
 mod worker;
 
 pub use args::arguments;
+pub use auth::{create_token, revoke_token, Session};
 pub use rpc::Rpc;
 pub use server::Server;
-pub use worker::{worker, Missive};
+pub use worker::{call_client, worker, Missive};
 
-pub fn init(args: &ArgMatches) -> (String, Bus<Missive>, JoinHandle<()>) {
+pub fn init(args: &ArgMatches) -> (String, Option<SslAcceptor>, Bus<Missive>, JoinHandle<()>) {
     let verbosity = args.occurrences_of("v") as i8 - args.occurrences_of("q") as i8;
     crate::init_with_level(verbosity);
 
@@ -22,9 +30,43 @@ pub fn init(args: &ArgMatches) -> (String, Bus<Missive>, JoinHandle<()>) {
     let port = args.value_of("port").expect("bad --port option");
     let server = format!("{}:{}", host, port);
 
+    let tls = args.value_of("cert").map(|cert| {
+        let key = args.value_of("key").expect("bad --key option");
+        crate::tls::acceptor(
+            Path::new(cert),
+            Path::new(key),
+            args.value_of("ca").map(Path::new),
+        )
+        .expect("failed to set up TLS")
+    });
+
     let (bus, terminal) = central();
     data::data_service(bus.clone());
+    git::git_service(bus.clone());
+    build::build_service(bus.clone());
+
+    info!(
+        "Setting up trebuchet on {}{}",
+        server,
+        if tls.is_some() { " with TLS" } else { "" }
+    );
+    (server, tls, bus, terminal)
+}
+
+/// Bus topic for builds to run.
+const BUILDS_TOPIC: &str = "builds";
+
+/// Bus topic for clients coming and going.
+const CLIENTS_TOPIC: &str = "clients";
+
+/// Bus topic for a release's build output and state changes.
+fn release_topic(id: i32) -> String {
+    format!("release:{}", id)
+}
 
-    info!("Setting up trebuchet on {}", server);
-    (server, bus, terminal)
+/// Where the castle keeps its working files (git mirrors, builds).
+fn data_dir() -> PathBuf {
+    env::var("TREBUCHET_DATA")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| env::temp_dir().join("trebuchet"))
 }
@@ -1,26 +1,144 @@
-use super::{data, Missive};
+use super::{artefact, auth, call_client, data, git, Missive, Session};
 use crate::client::Kind;
-use crate::db::models::App;
+use crate::db::{
+    models::{App, Client, Release, ReleaseLogs},
+    types::{ReleaseState, Role},
+};
 use crate::{
-    rpc::{app_error, RpcDelegate},
-    Bus,
+    api::{self, Castle, Greeting, Permit},
+    rpc::{app_error, param_list, RpcDelegate},
+    Bus, BusStats,
 };
-use jsonrpc_core::{Metadata, Result as RpcResult};
+use jsonrpc_core::{Metadata, Result as RpcResult, Value};
 use jsonrpc_macros::IoDelegate;
-use log::info;
+use log::{debug, error, info, warn};
 use regex::Regex;
-use rpc_impl_macro::{rpc, rpc_impl_struct};
 use serde_json::json;
+use std::env;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// How long targets get to deploy a release unless told otherwise, in seconds.
+const DEFAULT_DEPLOY_TIMEOUT: u64 = 600;
 
 #[derive(Clone)]
 pub struct Rpc {
     /// Castle bus
     bus: Bus<Missive>,
+
+    /// Own websocket end, to hang up on incompatible clients
+    sender: ws::Sender,
+
+    /// Token the connection authenticated with
+    session: Session,
 }
 
 impl Rpc {
-    pub fn new(bus: Bus<Missive>) -> Self {
-        Self { bus }
+    pub fn new(bus: Bus<Missive>, sender: ws::Sender, session: Session) -> Self {
+        Self {
+            bus,
+            sender,
+            session,
+        }
+    }
+
+    fn queue_build(&self, app: String, tag: String, rebuild: bool) -> RpcResult<Release> {
+        // queued before it's published, so following it right away doesn't find it as it was
+        let release = if let Missive::Release(release) =
+            data::request(&self.bus, data::Topic::QueueBuild { app, tag, rebuild })?
+        {
+            release
+        } else {
+            unreachable!()
+        };
+
+        info!("queueing build of {} ({})", release.tag, release.id);
+        self.bus
+            .publish(super::BUILDS_TOPIC, Missive::Build(release.clone()));
+        Ok(release)
+    }
+
+    /// Calls targets to deploy releases, and returns the target names.
+    ///
+    /// Each target is called from a thread of its own, which waits for the deploy to finish (or
+    /// fail, or time out) and reports the result back to this connection.
+    fn dispatch(&self, app: &str, plan: Vec<(Client, Release)>) -> Vec<String> {
+        self.bus.send_own(Missive::Deploying {
+            targets: plan.len(),
+        });
+
+        let timeout = deploy_timeout();
+        plan.into_iter()
+            .map(|(target, release)| {
+                let name = target.name.clone();
+                let bus = self.bus.clone();
+                let app = app.to_string();
+                thread::Builder::new()
+                    .name(format!("deploy {} to {}", release.tag, target.name))
+                    .spawn(move || {
+                        // targets store releases by artefact checksum, so rebuilds are fetched anew
+                        let error = artefact::read_checksum(release.id)
+                            .map_err(|err| format!("artefact not available: {}", err))
+                            .and_then(|checksum| {
+                                let params = param_list(vec![
+                                    json!(app),
+                                    json!(release.tag),
+                                    json!(release.id),
+                                    json!(checksum),
+                                ]);
+                                call_client(
+                                    &bus,
+                                    &target.connection,
+                                    "release:deploy",
+                                    params,
+                                    timeout,
+                                )
+                                .map_err(|err| err.message)
+                            })
+                            .err();
+
+                        bus.send_top(Missive::Deployed {
+                            target: target.connection,
+                            release: release.id,
+                            error: error.clone(),
+                        });
+                        bus.send_own(Missive::DeployResult {
+                            target: target.name,
+                            release: release.id,
+                            error,
+                        });
+                    })
+                    .expect("failed to start deploy thread");
+
+                name
+            })
+            .collect()
+    }
+}
+
+/// How long targets get to deploy a release, from `TREBUCHET_DEPLOY_TIMEOUT`.
+fn deploy_timeout() -> Duration {
+    let secs = env::var("TREBUCHET_DEPLOY_TIMEOUT")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(DEFAULT_DEPLOY_TIMEOUT);
+
+    Duration::from_secs(secs)
+}
+
+fn parse_filter(filter: Option<String>) -> RpcResult<Option<Regex>> {
+    if let Some(r) = filter {
+        Ok(Some(Regex::new(&r).map_err(|err| {
+            app_error(
+                400,
+                "filter is not a valid regexp",
+                Some(json!(err.to_string())),
+            )
+        })?))
+    } else {
+        Ok(None)
     }
 }
 
@@ -30,44 +148,394 @@ impl RpcDelegate for Rpc {
         M: Metadata,
         Self: Sized + Send + Sync,
     {
-        self.to_delegate()
+        let mut delegate = IoDelegate::new(Arc::new(self));
+        api::delegate(&mut delegate);
+        delegate
+    }
+}
+
+impl Permit for Rpc {
+    fn permit(&self, roles: &[Role]) -> RpcResult<()> {
+        self.session.permit(roles)
     }
 }
 
-rpc_impl_struct! {
-    impl Rpc {
-        #[rpc(notification)]
-        pub fn greetings(&self, app: String, kind: Kind, name: String, tags: Vec<String>) {
-            info!("received greetings from a {:?} client named \"{}\" with tags: {:?} running {}", kind, name, tags, app);
-            self.bus.send_top(Missive::Hello { app, kind, name, tags });
+impl Castle for Rpc {
+    fn greetings(
+        &self,
+        app: String,
+        kind: Kind,
+        name: String,
+        tags: Vec<String>,
+        identity: Uuid,
+    ) -> RpcResult<Greeting> {
+        info!(
+            "received greetings from a {:?} client named \"{}\" ({}) with tags: {:?} running {}",
+            kind, name, identity, tags, app
+        );
+
+        if let Err(reason) = api::check_version(&app) {
+            warn!("refusing {} client {}: {}", app, name, reason);
+            if let Err(err) = self
+                .sender
+                .close_with_reason(api::INCOMPATIBLE, reason.clone())
+            {
+                error!("failed to close connection: {:?}", err);
+            }
+
+            return Err(app_error(
+                426,
+                "incompatible client version",
+                Some(json!(reason)),
+            ));
         }
 
-        #[rpc(name = "apps:list")]
-        pub fn apps_list(&self, filter: Option<String>) -> RpcResult<Vec<App>> {
-            let filter = if let Some(r) = filter {
-                Some(Regex::new(&r).map_err(|err| app_error(
-                    400,
-                    "filter is not a valid regexp",
-                    Some(json!(err.to_string())))
-                )?)
-            } else {
-                None
-            };
+        self.session.permit(match kind {
+            Kind::Target => auth::TARGET,
+            Kind::Command => auth::READ,
+        })?;
+
+        // with mutual TLS, targets are whoever their certificate says they are
+        let name = match (&kind, self.session.certificate()) {
+            (Kind::Target, Some(ref certified)) if *certified != name => {
+                warn!(
+                    "target \"{}\" has a certificate for \"{}\", using the latter",
+                    name, certified
+                );
+                certified.clone()
+            }
+            _ => name,
+        };
 
-            Ok(if let Missive::AppList(list) = data::request(&self.bus, data::Topic::AppList { filter })? {
+        self.bus.send_top(Missive::Hello {
+            identity,
+            app,
+            kind,
+            name,
+            tags,
+        });
+        Ok(Greeting {
+            version: api::app_version(),
+            capabilities: api::CAPABILITIES.iter().map(|c| (*c).to_string()).collect(),
+            connection: self.bus.id,
+        })
+    }
+
+    fn apps_list(&self, filter: Option<String>) -> RpcResult<Vec<App>> {
+        let filter = parse_filter(filter)?;
+        Ok(
+            if let Missive::AppList(list) =
+                data::request(&self.bus, data::Topic::AppList { filter })?
+            {
                 list
             } else {
                 Vec::new()
-            })
+            },
+        )
+    }
+
+    fn apps_create(
+        &self,
+        name: String,
+        repo: String,
+        build_script: Option<String>,
+    ) -> RpcResult<App> {
+        if let Missive::App(app) = data::request(
+            &self.bus,
+            data::Topic::CreateApp {
+                name,
+                repo,
+                build_script,
+            },
+        )? {
+            Ok(app)
+        } else {
+            unreachable!()
         }
+    }
+
+    fn apps_delete(&self, name: String, force: bool) -> RpcResult<App> {
+        if let Missive::App(app) =
+            data::request(&self.bus, data::Topic::ArchiveApp { name, force })?
+        {
+            info!("archived app {}", app.name);
+            Ok(app)
+        } else {
+            unreachable!()
+        }
+    }
 
-        #[rpc(name = "apps:create")]
-        pub fn apps_create(&self, name: String, repo: String, build_script: Option<String>) -> RpcResult<App> {
-            if let Missive::App(app) = data::request(&self.bus, data::Topic::CreateApp { name, repo, build_script })? {
-                Ok(app)
+    fn apps_update(
+        &self,
+        name: String,
+        new_name: Option<String>,
+        repo: Option<String>,
+        build_script: Option<String>,
+    ) -> RpcResult<App> {
+        if let Missive::App(app) = data::request(
+            &self.bus,
+            data::Topic::UpdateApp {
+                name,
+                new_name,
+                repo,
+                build_script,
+            },
+        )? {
+            Ok(app)
+        } else {
+            unreachable!()
+        }
+    }
+
+    fn releases_sync(&self, app: String) -> RpcResult<Vec<Release>> {
+        let app = if let Missive::App(app) =
+            data::request(&self.bus, data::Topic::GetApp { name: app })?
+        {
+            app
+        } else {
+            unreachable!()
+        };
+
+        let tags = git::sync(&self.bus, app.id, &app.repo)?;
+        info!("found {} tags in {} repo", tags.len(), app.name);
+        Ok(
+            if let Missive::ReleaseList(list) =
+                data::request(&self.bus, data::Topic::SyncReleases { app, tags })?
+            {
+                list
             } else {
-                unreachable!()
+                Vec::new()
+            },
+        )
+    }
+
+    fn releases_list(
+        &self,
+        app: String,
+        filter: Option<String>,
+        status: Option<ReleaseState>,
+    ) -> RpcResult<Vec<Release>> {
+        let filter = parse_filter(filter)?;
+        Ok(
+            if let Missive::ReleaseList(list) = data::request(
+                &self.bus,
+                data::Topic::ReleaseList {
+                    app,
+                    filter,
+                    status,
+                },
+            )? {
+                list
+            } else {
+                Vec::new()
+            },
+        )
+    }
+
+    fn releases_build(&self, app: String, tag: String) -> RpcResult<Release> {
+        self.queue_build(app, tag, false)
+    }
+
+    fn releases_rebuild(&self, app: String, tag: String) -> RpcResult<Release> {
+        self.queue_build(app, tag, true)
+    }
+
+    fn releases_follow(&self, app: String, tag: String) -> RpcResult<ReleaseLogs> {
+        let release = if let Missive::Release(release) =
+            data::request(&self.bus, data::Topic::GetRelease { app, tag })?
+        {
+            release
+        } else {
+            unreachable!()
+        };
+
+        let follow = match release.state {
+            ReleaseState::Queued | ReleaseState::Building => {
+                info!("following build of {} ({})", release.tag, release.id);
+                // before fetching the logs so far, so nothing falls in between; lines streamed
+                // meanwhile are held by the worker, and those fetched here are dropped from them
+                self.bus.send_own(Missive::Following(release.id));
+                self.bus.subscribe(&super::release_topic(release.id));
+                true
+            }
+            _ => false,
+        };
+
+        let logs = data::request(
+            &self.bus,
+            data::Topic::BuildLogs {
+                release_id: release.id,
+            },
+        )
+        .map(|missive| {
+            if let Missive::BuildLogs(logs) = missive {
+                logs
+            } else {
+                Vec::new()
+            }
+        });
+
+        if follow {
+            self.bus.send_own(Missive::Followed {
+                release: release.id,
+                last: logs
+                    .as_ref()
+                    .ok()
+                    .and_then(|logs| logs.last())
+                    .map(|log| log.id),
+            });
+        }
+
+        Ok(ReleaseLogs {
+            release,
+            logs: logs?,
+        })
+    }
+
+    fn deploy(
+        &self,
+        app: String,
+        tag: String,
+        names: Vec<String>,
+        tags: Vec<String>,
+    ) -> RpcResult<Vec<String>> {
+        if names.is_empty() && tags.is_empty() {
+            return Err(app_error(400, "no targets selected", None));
+        }
+
+        let release = if let Missive::Release(release) = data::request(
+            &self.bus,
+            data::Topic::GetRelease {
+                app: app.clone(),
+                tag,
+            },
+        )? {
+            release
+        } else {
+            unreachable!()
+        };
+
+        match release.state {
+            ReleaseState::Ready => {}
+            _ => {
+                return Err(app_error(
+                    409,
+                    "release is not ready to deploy",
+                    Some(json!(release.tag)),
+                ))
             }
         }
+
+        let targets = if let Missive::ClientList(list) =
+            data::request(&self.bus, data::Topic::TargetList { names, tags })?
+        {
+            list
+        } else {
+            unreachable!()
+        };
+
+        if targets.is_empty() {
+            return Err(app_error(404, "no connected targets matched", None));
+        }
+
+        info!(
+            "deploying {} {} to {} targets",
+            app,
+            release.tag,
+            targets.len()
+        );
+        let plan = targets
+            .into_iter()
+            .map(|target| (target, release.clone()))
+            .collect();
+        Ok(self.dispatch(&app, plan))
+    }
+
+    fn rollback(
+        &self,
+        app: String,
+        to: Option<String>,
+        names: Vec<String>,
+        tags: Vec<String>,
+    ) -> RpcResult<Vec<(String, String)>> {
+        let plan = if let Missive::RollbackPlan(plan) = data::request(
+            &self.bus,
+            data::Topic::RollbackPlan {
+                app: app.clone(),
+                to,
+                names,
+                tags,
+            },
+        )? {
+            plan
+        } else {
+            unreachable!()
+        };
+
+        if plan.is_empty() {
+            return Err(app_error(404, "no connected targets to roll back", None));
+        }
+
+        info!("rolling back {} on {} targets", app, plan.len());
+        let tags: Vec<String> = plan
+            .iter()
+            .map(|(_, release)| release.tag.clone())
+            .collect();
+        Ok(self.dispatch(&app, plan).into_iter().zip(tags).collect())
+    }
+
+    fn clients_list(
+        &self,
+        kind: Option<Kind>,
+        filter: Option<String>,
+        tags: Vec<String>,
+        connected: Option<bool>,
+        watch: bool,
+    ) -> RpcResult<Vec<Client>> {
+        let filter = data::ClientFilter {
+            kind,
+            name: parse_filter(filter)?,
+            tags,
+            connected,
+        };
+
+        if watch {
+            self.bus.send_own(Missive::WatchClients(filter.clone()));
+        }
+
+        if let Missive::ClientList(list) =
+            data::request(&self.bus, data::Topic::ClientList { filter })?
+        {
+            Ok(list)
+        } else {
+            unreachable!()
+        }
+    }
+
+    fn bus_stats(&self) -> RpcResult<BusStats> {
+        Ok(self.bus.stats())
+    }
+
+    fn artefacts_read(&self, release: i32, offset: u64) -> RpcResult<Value> {
+        let (chunk, size, checksum) = artefact::read(release, offset).map_err(|err| {
+            app_error(
+                404,
+                "artefact not available",
+                Some(json!({ "release": release, "error": err.to_string() })),
+            )
+        })?;
+
+        debug!(
+            "sending {} bytes of artefact {} from {}",
+            chunk.len(),
+            release,
+            offset
+        );
+        Ok(json!({
+            "offset": offset,
+            "size": size,
+            "checksum": checksum,
+            ".raw": [chunk],
+        }))
     }
 }
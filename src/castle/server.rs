@@ -1,9 +1,12 @@
-use super::{worker, Missive};
+use super::{auth, worker, Missive, Session};
+use crate::heartbeat::{self, Heartbeat};
 use crate::inflight::Inflight;
-use crate::rpc::{RpcClient, RpcDelegate, RpcHandler};
-use crate::Bus;
-use jsonrpc_core::IoHandler;
-use log::debug;
+use crate::rpc::{Offload, RpcClient, RpcDelegate, RpcHandler};
+use crate::{api, tls, Bus};
+use jsonrpc_core::{IoHandler, Value};
+use log::{debug, error, info, warn};
+use mio::tcp::TcpStream;
+use openssl::ssl::{SslAcceptor, SslStream};
 use std::thread;
 
 pub struct Server {
@@ -18,33 +21,104 @@ pub struct Server {
 
     /// JSON-RPC server handlers
     rpc: IoHandler,
+
+    /// What the connection authenticated with, shared with the handlers
+    session: Session,
+
+    /// Token presented in the handshake, until it's checked
+    token: Option<String>,
+
+    /// Where the token is checked, away from the websocket thread
+    checks: Offload,
+
+    /// TLS settings, if the castle listens over TLS
+    tls: Option<SslAcceptor>,
+
+    /// Liveness of the client
+    heartbeat: Heartbeat,
 }
 
 impl Server {
-    pub fn create<R>(rpcd: R, sender: ws::Sender, bus: Bus<Missive>) -> Self
+    pub fn create<R>(
+        rpcd: R,
+        sender: ws::Sender,
+        bus: Bus<Missive>,
+        session: Session,
+        tls: Option<SslAcceptor>,
+    ) -> Self
     where
         R: RpcDelegate + Send + Sync + 'static,
     {
         let mut rpc = IoHandler::new();
         rpc.extend_with(rpcd.to_delegate());
 
-        let workws = sender.clone();
-        let workbus = bus.clone();
+        let server = Self {
+            bus,
+            inflight: Inflight::default(),
+            rpc,
+            sender,
+            session,
+            token: None,
+            checks: Offload::new("token checks"),
+            tls,
+            heartbeat: Heartbeat::default(),
+        };
+
+        let workremote = server.remote();
+        let workbus = server.bus.clone();
         thread::Builder::new()
-            .name(format!("worker thread {}", bus.id))
+            .name(format!("worker thread {}", workbus.id))
             .spawn(move || {
                 debug!("worker thread start {}", workbus.id);
-                worker(workws, workbus.clone());
+                worker(workremote, workbus.clone());
                 debug!("worker thread end {}", workbus.id);
             })
             .expect("failed to start worker thread");
 
-        Self {
-            bus,
-            inflight: Inflight::default(),
-            rpc,
-            sender,
-        }
+        server
+    }
+}
+
+impl Server {
+    /// Checks the token from the handshake, and hangs up if it's refused.
+    fn check_token(&mut self) {
+        let token = match self.token.take() {
+            Some(token) => token,
+            None => {
+                self.session.refuse();
+                return;
+            }
+        };
+
+        let bus = self.bus.clone();
+        let session = self.session.clone();
+        let sender = self.sender.clone();
+
+        // the check goes ahead whether or not anything waits on its result
+        drop(
+            self.checks
+                .run(move || match auth::authenticate(&bus, &token) {
+                    Ok(token) => {
+                        info!(
+                            "authenticated with token {} as {:?}",
+                            token.name, token.role
+                        );
+                        session.set(token);
+                        Ok(Value::Null)
+                    }
+                    Err(err) => {
+                        warn!("refusing connection {}: {}", bus.id, err.message);
+                        session.refuse();
+                        if let Err(err) =
+                            sender.close_with_reason(api::UNAUTHORIZED, err.message.clone())
+                        {
+                            error!("failed to close connection: {:?}", err);
+                        }
+
+                        Err(err)
+                    }
+                }),
+        );
     }
 }
 
@@ -73,19 +147,65 @@ impl RpcHandler for Server {
 }
 
 impl ws::Handler for Server {
+    fn upgrade_ssl_server(&mut self, sock: TcpStream) -> ws::Result<SslStream<TcpStream>> {
+        let acceptor = self.tls.as_ref().ok_or_else(|| {
+            ws::Error::new(ws::ErrorKind::Internal, "castle is not set up for TLS")
+        })?;
+
+        let session = self.session.clone();
+        tls::accept(acceptor, sock, move |name| session.set_certificate(name))
+    }
+
     fn on_request(&mut self, req: &ws::Request) -> ws::Result<ws::Response> {
-        self.rpc_on_request(req)
+        let res = self.rpc_on_request(req)?;
+
+        // the token itself is checked once the connection is open, as that can take a while
+        match auth::bearer(req) {
+            Some(token) => {
+                self.token = Some(token);
+                Ok(res)
+            }
+            None => {
+                warn!("refusing connection {}: missing token", self.bus.id);
+                Ok(ws::Response::new(
+                    401,
+                    "Unauthorized",
+                    b"missing token".to_vec(),
+                ))
+            }
+        }
     }
 
     fn on_open(&mut self, _shake: ws::Handshake) -> ws::Result<()> {
         debug!("connection accepted");
-        Ok(())
+        self.check_token();
+        self.heartbeat.start(&self.sender)
+    }
+
+    fn on_frame(&mut self, frame: ws::Frame) -> ws::Result<Option<ws::Frame>> {
+        self.heartbeat.seen();
+        Ok(Some(frame))
+    }
+
+    fn on_timeout(&mut self, event: ws::util::Token) -> ws::Result<()> {
+        if event != heartbeat::BEAT || self.heartbeat.beat(&self.sender)? {
+            return Ok(());
+        }
+
+        // The close handshake may never complete, so record the client gone right away
+        warn!("client {} stopped responding, dropping it", self.bus.id);
+        self.bus.send_own(Missive::Exit);
+        self.sender.close(ws::CloseCode::Away)
     }
 
     fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
         self.rpc_on_message(msg)
     }
 
+    fn on_close(&mut self, code: ws::CloseCode, reason: &str) {
+        self.rpc_on_close(code, reason)
+    }
+
     fn on_shutdown(&mut self) {
         self.rpc_on_shutdown()
     }
@@ -1,36 +1,364 @@
 use crate::client::Kind;
-use crate::db::models::App;
-use crate::Bus;
-use crossbeam_channel::Sender;
-use jsonrpc_core::Result as RpcResult;
-use log::trace;
+use crate::db::{
+    models::{App, BuildLog, Client, Release, Token},
+    types::ReleaseState,
+};
+use crate::rpc::{app_error, param_list, RpcClient, RpcRemote};
+use crate::{dispatch, Bus, Incoming, RequestError};
+use jsonrpc_core::{futures::Future, Error as RpcError, Params, Result as RpcResult, Value};
+use log::{debug, error, trace, warn};
+use serde_json::json;
+use std::{collections::HashMap, time::Duration};
+use uuid::Uuid;
 
 #[derive(Clone, Debug)]
 pub enum Missive {
     Exit,
     Hello {
+        identity: Uuid,
         app: String,
         kind: Kind,
         name: String,
         tags: Vec<String>,
     },
-    DataRequest {
-        topic: super::data::Topic,
-        tx: Sender<RpcResult<Missive>>,
+    DataRequest(super::data::Topic),
+    Call {
+        method: String,
+        params: Params,
+        timeout: Duration,
     },
+    Called(Value),
+    Error(RpcError),
     App(App),
     AppList(Vec<App>),
+    Build(Release),
+    BuildLog(BuildLog),
+    BuildLogs(Vec<BuildLog>),
+    BuildOutput(BuildLog),
+    BuildState(Release),
+    ClientList(Vec<Client>),
+    Mirror {
+        app_id: i32,
+        repo: String,
+    },
+    Deployed {
+        target: Uuid,
+        release: i32,
+        error: Option<String>,
+    },
+    DeployResult {
+        target: String,
+        release: i32,
+        error: Option<String>,
+    },
+    Deploying {
+        targets: usize,
+    },
+    /// Build output of the release is about to be fetched: hold its streamed lines until then
+    Following(i32),
+    /// Build output of the release was fetched, up to the given log line
+    Followed {
+        release: i32,
+        last: Option<i32>,
+    },
+    Presence(Client),
+    Release(Release),
+    ReleaseList(Vec<Release>),
+    RollbackPlan(Vec<(Client, Release)>),
+    Tags(Vec<String>),
+    Token(Token),
+    WatchClients(super::data::ClientFilter),
+}
+
+/// Calls a method on a connected client, and waits for its response.
+///
+/// Clients are addressed by connection id, which is the id of their bus (as stored in
+/// `clients.connection`). The call is made by the client's worker, and fails if the client is
+/// gone, goes away before responding, or takes longer than `timeout` to respond.
+///
+/// This blocks for up to `timeout`. Castle API handlers can use it, as they run on a connection's
+/// `Offload` thread, but that holds up the connection's other calls meanwhile. Don't use it from
+/// the websocket thread, which is the one that would receive the response.
+pub fn call_client(
+    bus: &Bus<Missive>,
+    connection: &Uuid,
+    method: &str,
+    params: Params,
+    timeout: Duration,
+) -> RpcResult<Value> {
+    trace!("routing {} call to client {}", method, connection);
+    let call = Missive::Call {
+        method: method.into(),
+        params,
+        timeout,
+    };
+
+    let details = Some(json!({ "connection": connection, "method": method }));
+    match bus.request(connection, call, timeout) {
+        Ok(Missive::Called(value)) => Ok(value),
+        Ok(Missive::Error(err)) => Err(err),
+        Ok(other) => Err(app_error(
+            66,
+            "unexpected reply",
+            Some(json!(format!("{:?}", other))),
+        )),
+        Err(RequestError::Gone) => Err(app_error(410, "client is not connected", details)),
+        Err(RequestError::Timeout) => Err(app_error(408, "client call timed out", details)),
+    }
 }
 
-pub fn worker(_ws: ws::Sender, bus: Bus<Missive>) {
-    for missive in bus.iter() {
+pub fn worker(remote: RpcRemote, bus: Bus<Missive>) {
+    // Clients this connection is watching come and go
+    let mut watching: Option<super::data::ClientFilter> = None;
+
+    // Streamed build output held while the log so far is fetched, by release
+    let mut held: HashMap<i32, Vec<BuildLog>> = HashMap::new();
+
+    // Last log line fetched for each followed release, as streamed lines up to it were sent already
+    let mut fetched: HashMap<i32, i32> = HashMap::new();
+
+    // Deploys this (command) connection is waiting on, as a count of targets left
+    let mut awaiting: usize = 0;
+
+    // Whether the connection closed, rather than the bus giving up on it
+    let mut exited = false;
+
+    for Incoming {
+        content: missive,
+        request,
+        ..
+    } in bus.incoming()
+    {
         trace!("received bus message: {:?}", missive);
         match missive {
             Missive::Exit => {
-                bus.send_top(Missive::Exit);
+                exited = true;
                 break;
             }
+            Missive::Call {
+                method,
+                params,
+                timeout,
+            } => {
+                let request = match request {
+                    Some(request) => request,
+                    None => {
+                        warn!("got a {} call with nowhere to reply, ignoring", method);
+                        continue;
+                    }
+                };
+
+                debug!("calling {} on behalf of the castle", method);
+                match remote.call_async_timeout(&method, params, &[], timeout) {
+                    Ok(call) => {
+                        let cbbus = bus.clone();
+                        dispatch::spawn(call.then(move |res| {
+                            cbbus.reply(
+                                request,
+                                match res {
+                                    Ok(value) => Missive::Called(value),
+                                    Err(err) => Missive::Error(err),
+                                },
+                            );
+                            Ok(())
+                        }));
+                    }
+                    Err(err) => bus.reply(
+                        request,
+                        Missive::Error(app_error(
+                            410,
+                            "failed to call client",
+                            Some(json!(err.to_string())),
+                        )),
+                    ),
+                }
+            }
+            Missive::WatchClients(filter) => {
+                bus.subscribe(super::CLIENTS_TOPIC);
+                watching = Some(filter);
+            }
+            Missive::Presence(client) => {
+                if watching
+                    .as_ref()
+                    .map_or(false, |filter| filter.matches(&client))
+                {
+                    notify(&remote, "clients:presence", vec![json!(client)]);
+                }
+            }
+            Missive::Following(release) => {
+                held.insert(release, Vec::new());
+            }
+            Missive::Followed { release, last } => {
+                if let Some(last) = last {
+                    fetched.insert(release, last);
+                }
+
+                for log in held.remove(&release).unwrap_or_default() {
+                    if last.map_or(true, |last| log.id > last) {
+                        notify(&remote, "releases:log", vec![json!(log)]);
+                    }
+                }
+            }
+            Missive::BuildOutput(log) => {
+                if let Some(logs) = held.get_mut(&log.release_id) {
+                    logs.push(log);
+                } else if fetched
+                    .get(&log.release_id)
+                    .map_or(true, |&last| log.id > last)
+                {
+                    notify(&remote, "releases:log", vec![json!(log)]);
+                }
+            }
+            Missive::BuildState(release) => {
+                match release.state {
+                    ReleaseState::Ready | ReleaseState::Failed => {
+                        bus.unsubscribe(&super::release_topic(release.id));
+                        fetched.remove(&release.id);
+                    }
+                    _ => {}
+                }
+
+                notify(&remote, "releases:state", vec![json!(release)]);
+            }
+            Missive::Deploying { targets } => {
+                awaiting += targets;
+            }
+            Missive::DeployResult {
+                target,
+                release,
+                error,
+            } => {
+                notify(
+                    &remote,
+                    "deploy:result",
+                    vec![json!(target), json!(release), json!(error)],
+                );
+
+                if awaiting > 0 {
+                    awaiting -= 1;
+                    if awaiting == 0 {
+                        notify(&remote, "deploy:done", Vec::new());
+                    }
+                }
+            }
             _ => {}
         }
     }
+
+    if !exited {
+        warn!("connection {} fell too far behind, hanging up", bus.id);
+        if let Err(err) = remote.kill(Some(ws::CloseCode::Again)) {
+            error!("failed to close connection: {:?}", err);
+        }
+    }
+
+    bus.send_top(Missive::Exit);
+}
+
+fn notify(remote: &RpcRemote, method: &str, params: Vec<Value>) {
+    if let Err(err) = remote.notify(method, param_list(params)) {
+        error!("failed to notify client of {}: {:?}", method, err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::central;
+    use jsonrpc_core::ErrorCode;
+    use std::thread;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Stands in for a client's worker, answering calls with `answer`.
+    fn client<F>(bus: &Bus<Missive>, answer: F) -> Uuid
+    where
+        F: Fn(String, Params) -> Option<Missive> + Send + 'static,
+    {
+        let bus = bus.clone().launch();
+        let id = bus.id;
+        thread::spawn(move || {
+            for incoming in bus.incoming() {
+                if let (Missive::Call { method, params, .. }, Some(request)) =
+                    (incoming.content, incoming.request)
+                {
+                    if let Some(reply) = answer(method, params) {
+                        bus.reply(request, reply);
+                    }
+                }
+            }
+        });
+
+        id
+    }
+
+    fn code(err: &RpcError) -> i64 {
+        match err.code {
+            ErrorCode::ServerError(code) => code,
+            _ => panic!("unexpected error: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn routes_calls_and_responses() {
+        let (bus, _) = central();
+        let connection = client(&bus, |method, params| {
+            Some(Missive::Called(
+                json!({ "method": method, "params": params }),
+            ))
+        });
+
+        let value = call_client(
+            &bus,
+            &connection,
+            "release:deploy",
+            param_list(vec![json!("app"), json!(1)]),
+            TIMEOUT,
+        )
+        .unwrap();
+
+        assert_eq!(value["method"], json!("release:deploy"));
+        assert_eq!(value["params"], json!(["app", 1]));
+        bus.kill();
+    }
+
+    #[test]
+    fn passes_errors_back() {
+        let (bus, _) = central();
+        let connection = client(&bus, |_, _| {
+            Some(Missive::Error(app_error(500, "no such release", None)))
+        });
+
+        let err =
+            call_client(&bus, &connection, "release:deploy", Params::None, TIMEOUT).unwrap_err();
+        assert_eq!(code(&err), 500);
+        assert_eq!(err.message, "no such release");
+        bus.kill();
+    }
+
+    #[test]
+    fn fails_right_away_for_unknown_clients() {
+        let (bus, _) = central();
+        let err =
+            call_client(&bus, &Uuid::new_v4(), "greetings", Params::None, TIMEOUT).unwrap_err();
+        assert_eq!(code(&err), 410);
+        bus.kill();
+    }
+
+    #[test]
+    fn times_out_on_silent_clients() {
+        let (bus, _) = central();
+        let connection = client(&bus, |_, _| None);
+
+        let err = call_client(
+            &bus,
+            &connection,
+            "greetings",
+            Params::None,
+            Duration::from_millis(100),
+        )
+        .unwrap_err();
+        assert_eq!(code(&err), 408);
+        bus.kill();
+    }
 }
@@ -20,6 +20,43 @@ pub fn arguments<'a, 'b>(name: &str) -> App<'a, 'b> {
                 .takes_value(true)
                 .default_value("9077"),
         )
+        .arg(
+            Arg::with_name("token")
+                .long("token")
+                .value_name("TOKEN")
+                .help("Authenticates to the server with an access token")
+                .takes_value(true)
+                .env("TREBUCHET_TOKEN")
+                .hide_env_values(true),
+        )
+        .arg(
+            Arg::with_name("tls")
+                .long("tls")
+                .help("Connects over TLS (implied by --ca and --cert)"),
+        )
+        .arg(
+            Arg::with_name("ca")
+                .long("ca")
+                .value_name("PEM")
+                .help("Verifies the server against this CA instead of the system roots")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cert")
+                .long("cert")
+                .value_name("PEM")
+                .help("Identifies to the server with this client certificate")
+                .takes_value(true)
+                .requires("key"),
+        )
+        .arg(
+            Arg::with_name("key")
+                .long("key")
+                .value_name("PEM")
+                .help("Sets the private key for --cert")
+                .takes_value(true)
+                .requires("cert"),
+        )
         .arg(
             Arg::with_name("name")
                 .long("name")
@@ -28,6 +65,14 @@ pub fn arguments<'a, 'b>(name: &str) -> App<'a, 'b> {
                 .takes_value(true)
                 .default_value(&crate::HOSTNAME),
         )
+        .arg(
+            Arg::with_name("identity")
+                .long("identity")
+                .value_name("UUID")
+                .help("Identifies to the server across reconnects and restarts (generated and kept otherwise)")
+                .takes_value(true)
+                .env("TREBUCHET_IDENTITY"),
+        )
         .arg(
             Arg::with_name("tags")
                 .long("tags")
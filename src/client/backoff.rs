@@ -0,0 +1,129 @@
+//! Delays between reconnection attempts.
+
+use rand::Rng;
+use std::time::Duration;
+
+/// Connections that last at least this long reset the backoff.
+pub const STABLE: Duration = Duration::from_secs(30);
+
+/// Exponential backoff with jitter.
+///
+/// Each delay is picked at random in the upper half of a ceiling that doubles with every failed
+/// attempt, up to a maximum. The randomness keeps a fleet of targets from all reconnecting at the
+/// same instant when the castle comes back.
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            attempt: 0,
+        }
+    }
+
+    /// Forgets past failures, once a connection has held up.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// How long to wait before the next attempt, after a connection that lasted so long.
+    pub fn next_delay_after(&mut self, lasted: Duration) -> Duration {
+        if lasted >= STABLE {
+            self.reset();
+        }
+
+        self.next_delay()
+    }
+
+    /// How long to wait before the next attempt.
+    pub fn next_delay(&mut self) -> Duration {
+        let ceiling = self
+            .base
+            .checked_mul(1 << self.attempt.min(16))
+            .map_or(self.max, |delay| delay.min(self.max));
+        self.attempt = self.attempt.saturating_add(1);
+
+        let half = millis(ceiling) / 2;
+        Duration::from_millis(half + rand::thread_rng().gen_range(0, half + 1))
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backoff() -> Backoff {
+        Backoff::new(Duration::from_secs(1), Duration::from_secs(60))
+    }
+
+    fn assert_within(delay: Duration, ceiling_ms: u64) {
+        let ms = millis(delay);
+        assert!(
+            ms >= ceiling_ms / 2 && ms <= ceiling_ms,
+            "{}ms is outside {}ms to {}ms",
+            ms,
+            ceiling_ms / 2,
+            ceiling_ms
+        );
+    }
+
+    #[test]
+    fn delays_double_with_jitter() {
+        for _ in 0..50 {
+            let mut backoff = backoff();
+            for ceiling in &[1000, 2000, 4000, 8000, 16_000, 32_000] {
+                assert_within(backoff.next_delay(), *ceiling);
+            }
+        }
+    }
+
+    #[test]
+    fn delays_are_capped() {
+        let mut backoff = backoff();
+        for _ in 0..6 {
+            backoff.next_delay();
+        }
+
+        // well past where the ceiling would overflow without the cap
+        for _ in 0..100 {
+            assert_within(backoff.next_delay(), 60_000);
+        }
+    }
+
+    #[test]
+    fn reset_starts_over() {
+        let mut backoff = backoff();
+        for _ in 0..10 {
+            backoff.next_delay();
+        }
+
+        backoff.reset();
+        assert_within(backoff.next_delay(), 1000);
+        assert_within(backoff.next_delay(), 2000);
+    }
+
+    #[test]
+    fn stable_connections_reset() {
+        let mut backoff = backoff();
+        for _ in 0..10 {
+            backoff.next_delay();
+        }
+
+        assert_within(
+            backoff.next_delay_after(STABLE - Duration::from_secs(1)),
+            60_000,
+        );
+        assert_within(backoff.next_delay_after(STABLE), 1000);
+        assert_within(backoff.next_delay_after(Duration::from_secs(0)), 2000);
+        assert_within(backoff.next_delay_after(STABLE * 10), 1000);
+    }
+}
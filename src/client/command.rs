@@ -1,15 +1,28 @@
+use super::Kind;
 use crate::{
-    db::models,
-    rpc::{param_list, RpcClient, RpcDelegate, RpcRemote},
+    api::{Castle, CastleClient},
+    db::{
+        models,
+        types::{LogStream, ReleaseState},
+    },
+    rpc::{RpcClient, RpcDelegate, RpcRemote},
 };
-use clap::{App, Arg, ArgMatches, SubCommand};
-use jsonrpc_core::{Metadata, Value};
+use clap::{App, Arg, ArgGroup, ArgMatches, SubCommand};
+use jsonrpc_core::Metadata;
 use jsonrpc_macros::IoDelegate;
 use log::{error, info, warn};
 use rpc_impl_macro::{rpc, rpc_impl_struct};
-use serde_json::{from_value, json};
 
-pub struct Rpc;
+pub struct Rpc {
+    /// Own websocket end, to hang up once a followed build or deploy is done
+    sender: ws::Sender,
+}
+
+impl Rpc {
+    pub fn new(sender: ws::Sender) -> Self {
+        Self { sender }
+    }
+}
 
 impl RpcDelegate for Rpc {
     fn to_delegate<M>(self) -> IoDelegate<Self, M>
@@ -35,7 +48,48 @@ pub fn arguments<'a, 'b>() -> App<'a, 'b> {
                         .takes_value(true),
                 ),
         )
-        .subcommand(SubCommand::with_name("apps:edit").about("reconfigure an app"))
+        .subcommand(
+            SubCommand::with_name("apps:edit")
+                .about("reconfigure an app")
+                .arg(app_arg())
+                .arg(
+                    Arg::with_name("rename")
+                        .long("rename")
+                        .value_name("NAME")
+                        .help("New name for the app")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("repo")
+                        .long("repo")
+                        .value_name("REPO")
+                        .help("New source git repository. Supports `github:user/repo` shorthand")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("build_script")
+                        .long("build-script")
+                        .value_name("SCRIPT")
+                        .help("New custom script to build the app")
+                        .takes_value(true),
+                )
+                .group(
+                    ArgGroup::with_name("changes")
+                        .args(&["rename", "repo", "build_script"])
+                        .multiple(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("apps:delete")
+                .about("archive an app")
+                .arg(app_arg())
+                .arg(
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Also archive the app's releases, even if deployed"),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("apps:create")
                 .about("configure a new app")
@@ -61,10 +115,60 @@ pub fn arguments<'a, 'b>() -> App<'a, 'b> {
                         .required(true),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("clients:list")
+                .about("list clients and when they were last seen")
+                .visible_alias("clients")
+                .arg(
+                    Arg::with_name("filter")
+                        .value_name("FILTER")
+                        .help("Regexp filter over the client names")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("kind")
+                        .long("kind")
+                        .value_name("KIND")
+                        .help("Show only clients of a particular kind")
+                        .takes_value(true)
+                        .possible_values(&["target", "command"]),
+                )
+                .arg(
+                    Arg::with_name("tagged")
+                        .long("tagged")
+                        .value_name("TAG")
+                        .help("Show only clients with this tag")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("connected")
+                        .long("connected")
+                        .help("Show only connected clients")
+                        .conflicts_with("disconnected"),
+                )
+                .arg(
+                    Arg::with_name("disconnected")
+                        .long("disconnected")
+                        .help("Show only disconnected clients"),
+                )
+                .arg(
+                    Arg::with_name("watch")
+                        .long("watch")
+                        .short("w")
+                        .help("Keep watching clients come and go"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("bus:stats")
+                .about("show messages the castle dropped for slow connections"),
+        )
         .subcommand(
             SubCommand::with_name("releases:list")
                 .about("list releases for an app")
                 .visible_alias("releases")
+                .arg(app_arg())
                 .arg(
                     Arg::with_name("filter")
                         .value_name("FILTER")
@@ -77,33 +181,130 @@ pub fn arguments<'a, 'b>() -> App<'a, 'b> {
                         .value_name("STATUS")
                         .help("Show only releases of a particular status")
                         .takes_value(true)
-                        .possible_values(&["ready", "building", "todo"]),
+                        .possible_values(&["ready", "building", "queued", "todo", "failed"]),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("deploy")
+                .about("deploy a ready release to targets")
+                .arg(app_arg())
+                .arg(tag_arg())
+                .arg(
+                    Arg::with_name("target")
+                        .long("target")
+                        .value_name("NAME")
+                        .help("Deploy to targets with this name")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("tagged")
+                        .long("tagged")
+                        .value_name("TAG")
+                        .help("Deploy to targets with this tag")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .group(
+                    ArgGroup::with_name("targets")
+                        .args(&["target", "tagged"])
+                        .multiple(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rollback")
+                .about("re-activate the previous release on targets")
+                .arg(app_arg())
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .value_name("TAG")
+                        .help("Roll back to this release instead of the previous one")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("target")
+                        .long("target")
+                        .value_name("NAME")
+                        .help("Roll back targets with this name (default: all with the app)")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("tagged")
+                        .long("tagged")
+                        .value_name("TAG")
+                        .help("Roll back targets with this tag (default: all with the app)")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
                 ),
         )
-        .subcommand(SubCommand::with_name("releases:sync").about("sync releases from source repo"))
-        .subcommand(SubCommand::with_name("releases:build").about("build a specific release"))
-        .subcommand(SubCommand::with_name("releases:rebuild").about("rebuild a release"))
+        .subcommand(
+            SubCommand::with_name("releases:sync")
+                .about("sync releases from source repo")
+                .arg(app_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("releases:build")
+                .about("build a specific release")
+                .arg(app_arg())
+                .arg(tag_arg())
+                .arg(follow_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("releases:rebuild")
+                .about("rebuild a release")
+                .arg(app_arg())
+                .arg(tag_arg())
+                .arg(follow_arg()),
+        )
+}
+
+fn app_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("app")
+        .value_name("APP")
+        .help("Name of the app")
+        .takes_value(true)
+        .required(true)
+}
+
+fn follow_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("follow")
+        .long("follow")
+        .short("f")
+        .help("Tail the build output until it's done")
+}
+
+fn tag_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("tag")
+        .value_name("TAG")
+        .help("Release version (git tag)")
+        .takes_value(true)
+        .required(true)
 }
 
 pub fn handler(remote: RpcRemote, args: ArgMatches) {
-    let cr = remote.clone();
+    let castle = CastleClient::new(remote.clone());
     let close = move || {
-        cr.kill(None).expect("failed to kill socket");
+        remote.kill(None).expect("failed to kill socket");
     };
 
-    let r = if let Some(args) = args.subcommand_matches("apps:list") {
-        let has_filter = args.value_of("filter").is_some();
-        let filter = args
-            .value_of("filter")
-            .map(|s| Value::String(s.into()))
-            .unwrap_or(json!(null));
-
-        remote.call("apps:list", param_list(vec![filter]), move |res| {
-            let apps: Vec<models::App> = from_value(res.map_err(|err| {
-                close();
-                err
-            })?)?;
+    let values = |args: &ArgMatches, name: &str| -> Vec<String> {
+        args.values_of(name)
+            .map(|vs| vs.map(String::from).collect())
+            .unwrap_or(Vec::new())
+    };
 
+    // Whether the command is done once its call returns, rather than waiting on notifications
+    let done = if let Some(args) = args.subcommand_matches("apps:list") {
+        let filter = args.value_of("filter").map(String::from);
+        let has_filter = filter.is_some();
+        castle.apps_list(filter).map(|apps| {
             if apps.is_empty() {
                 if has_filter {
                     error!("no apps matched! perhaps check your filter");
@@ -117,37 +318,329 @@ pub fn handler(remote: RpcRemote, args: ArgMatches) {
                 }
             }
 
-            close();
-            Ok(())
+            true
         })
     } else if let Some(args) = args.subcommand_matches("apps:create") {
-        let name = Value::String(args.value_of("name").unwrap().into());
-        let repo = Value::String(args.value_of("repo").unwrap().into());
-        let build_script = args
-            .value_of("build_script")
-            .map(|s| Value::String(s.into()))
-            .unwrap_or(json!(null));
-
-        remote.call(
-            "apps:create",
-            param_list(vec![name, repo, build_script]),
-            move |res| {
-                res.map(|_| {
-                    info!("done");
-                    close();
-                })
-                .map_err(|err| {
-                    close();
-                    err.into()
-                })
-            },
-        )
+        castle
+            .apps_create(
+                args.value_of("name").unwrap().into(),
+                args.value_of("repo").unwrap().into(),
+                args.value_of("build_script").map(String::from),
+            )
+            .map(|_| {
+                info!("done");
+                true
+            })
+    } else if let Some(args) = args.subcommand_matches("apps:edit") {
+        castle
+            .apps_update(
+                args.value_of("app").unwrap().into(),
+                args.value_of("rename").map(String::from),
+                args.value_of("repo").map(String::from),
+                args.value_of("build_script").map(String::from),
+            )
+            .map(|app| {
+                info!(
+                    "updated {} ({}, built with {})",
+                    app.name, app.repo, app.build_script
+                );
+                true
+            })
+    } else if let Some(args) = args.subcommand_matches("apps:delete") {
+        castle
+            .apps_delete(
+                args.value_of("app").unwrap().into(),
+                args.is_present("force"),
+            )
+            .map(|app| {
+                info!("archived {}", app.name);
+                true
+            })
+    } else if let Some(args) = args.subcommand_matches("releases:list") {
+        let has_filter = args.value_of("filter").is_some() || args.value_of("status").is_some();
+        let status = match args.value_of("status") {
+            Some("ready") => Some(ReleaseState::Ready),
+            Some("building") => Some(ReleaseState::Building),
+            Some("queued") => Some(ReleaseState::Queued),
+            Some("todo") => Some(ReleaseState::Todo),
+            Some("failed") => Some(ReleaseState::Failed),
+            _ => None,
+        };
+
+        castle
+            .releases_list(
+                args.value_of("app").unwrap().into(),
+                args.value_of("filter").map(String::from),
+                status,
+            )
+            .map(|releases| {
+                if releases.is_empty() {
+                    if has_filter {
+                        error!("no releases matched! perhaps check your filters");
+                    } else {
+                        warn!("no releases yet, try releases:sync");
+                    }
+                } else {
+                    info!("showing {} releases:", releases.len());
+                    print_releases(&releases);
+                }
+
+                true
+            })
+    } else if let Some(args) = args.subcommand_matches("clients:list") {
+        let watch = args.is_present("watch");
+        let kind = match args.value_of("kind") {
+            Some("target") => Some(Kind::Target),
+            Some("command") => Some(Kind::Command),
+            _ => None,
+        };
+        let connected = if args.is_present("connected") {
+            Some(true)
+        } else if args.is_present("disconnected") {
+            Some(false)
+        } else {
+            None
+        };
+
+        castle
+            .clients_list(
+                kind,
+                args.value_of("filter").map(String::from),
+                values(args, "tagged"),
+                connected,
+                watch,
+            )
+            .map(|clients| {
+                if clients.is_empty() {
+                    warn!("no clients matched");
+                } else {
+                    info!("showing {} clients:", clients.len());
+                    print_clients(&clients);
+                }
+
+                if watch {
+                    info!("watching for clients coming and going...");
+                }
+
+                !watch
+            })
+    } else if args.subcommand_matches("bus:stats").is_some() {
+        castle.bus_stats().map(|stats| {
+            info!(
+                "{} messages dropped, {} connections dropped for falling behind",
+                stats.dropped, stats.disconnected
+            );
+            for (bus, dropped) in &stats.buses {
+                info!("{}: {} messages dropped", bus, dropped);
+            }
+
+            true
+        })
+    } else if let Some(args) = args.subcommand_matches("deploy") {
+        castle
+            .deploy(
+                args.value_of("app").unwrap().into(),
+                args.value_of("tag").unwrap().into(),
+                values(args, "target"),
+                values(args, "tagged"),
+            )
+            .map(|targets| {
+                info!(
+                    "deploying to {} targets: {}",
+                    targets.len(),
+                    targets.join(", ")
+                );
+                false
+            })
+    } else if let Some(args) = args.subcommand_matches("rollback") {
+        castle
+            .rollback(
+                args.value_of("app").unwrap().into(),
+                args.value_of("to").map(String::from),
+                values(args, "target"),
+                values(args, "tagged"),
+            )
+            .map(|plan| {
+                info!("rolling back {} targets:", plan.len());
+                for (target, tag) in &plan {
+                    info!("{} to {}", target, tag);
+                }
+
+                false
+            })
+    } else if let Some(args) = args.subcommand_matches("releases:sync") {
+        castle
+            .releases_sync(args.value_of("app").unwrap().into())
+            .map(|releases| {
+                if releases.is_empty() {
+                    info!("no new releases");
+                } else {
+                    info!("found {} new releases:", releases.len());
+                    for release in &releases {
+                        info!("{}", release.tag);
+                    }
+                }
+
+                true
+            })
+    } else if let Some((rebuild, args)) = args
+        .subcommand_matches("releases:build")
+        .map(|sub| (false, sub))
+        .or_else(|| {
+            args.subcommand_matches("releases:rebuild")
+                .map(|sub| (true, sub))
+        })
+    {
+        let app: String = args.value_of("app").unwrap().into();
+        let tag: String = args.value_of("tag").unwrap().into();
+        let follow = args.is_present("follow");
+
+        if rebuild {
+            castle.releases_rebuild(app.clone(), tag.clone())
+        } else {
+            castle.releases_build(app.clone(), tag.clone())
+        }
+        .and_then(|release| {
+            info!("queued build of {}", release.tag);
+            if !follow {
+                return Ok(true);
+            }
+
+            castle.releases_follow(app, tag).map(|follow| {
+                for log in &follow.logs {
+                    print_log(log);
+                }
+
+                report_state(&follow.release)
+            })
+        })
     } else {
         error!("missing command");
-        return close();
+        Ok(true)
     };
 
-    r.expect("failed to send command");
+    match done {
+        Ok(false) => {}
+        Ok(true) => close(),
+        Err(err) => {
+            match err.data {
+                Some(data) => error!("{} ({})", err.message, data),
+                None => error!("{}", err.message),
+            }
+
+            close();
+        }
+    }
+}
+
+/// Prints a table of releases.
+fn print_releases(releases: &[models::Release]) {
+    print_table(
+        &["TAG", "STATE", "CREATED", "UPDATED"],
+        releases
+            .iter()
+            .map(|release| {
+                vec![
+                    release.tag.clone(),
+                    format!("{:?}", release.state).to_lowercase(),
+                    release.created.format("%Y-%m-%d %H:%M:%S").to_string(),
+                    release.updated.format("%Y-%m-%d %H:%M:%S").to_string(),
+                ]
+            })
+            .collect(),
+    );
+}
+
+/// Prints a table of clients.
+fn print_clients(clients: &[models::Client]) {
+    print_table(
+        &["NAME", "KIND", "VERSION", "TAGS", "LAST SEEN"],
+        clients
+            .iter()
+            .map(|client| {
+                vec![
+                    client.name.clone(),
+                    client_kind(client).into(),
+                    client.app.clone(),
+                    client
+                        .tags
+                        .as_ref()
+                        .map_or(String::new(), |ts| ts.join(",")),
+                    if client.connected {
+                        "connected".into()
+                    } else {
+                        client.updated.format("%Y-%m-%d %H:%M:%S").to_string()
+                    },
+                ]
+            })
+            .collect(),
+    );
+}
+
+fn client_kind(client: &models::Client) -> &'static str {
+    if client.target {
+        "target"
+    } else {
+        "command"
+    }
+}
+
+/// Prints rows in columns, under a header.
+fn print_table(header: &[&str], rows: Vec<Vec<String>>) {
+    let header: Vec<String> = header.iter().map(|cell| (*cell).to_string()).collect();
+
+    let mut widths = vec![0; header.len()];
+    for row in rows.iter().chain(Some(&header)) {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    for row in Some(&header).into_iter().chain(rows.iter()) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths.iter())
+            .map(|(cell, width)| format!("{:w$}", cell, w = width))
+            .collect();
+        info!("{}", line.join("  ").trim_end());
+    }
+}
+
+/// Prints a line of build output to the matching stream.
+fn print_log(log: &models::BuildLog) {
+    match log.stream {
+        LogStream::Stdout => println!("{}", log.line),
+        LogStream::Stderr => eprintln!("{}", log.line),
+    }
+}
+
+/// Reports on the state of a release build, and returns whether it's done.
+fn report_state(release: &models::Release) -> bool {
+    match release.state {
+        ReleaseState::Ready => {
+            info!("build of {} is ready", release.tag);
+            true
+        }
+        ReleaseState::Failed => {
+            error!(
+                "build of {} failed (status: {:?})",
+                release.tag, release.build_status
+            );
+            true
+        }
+        ReleaseState::Building => {
+            info!("build of {} is running", release.tag);
+            false
+        }
+        ReleaseState::Queued => {
+            info!("build of {} is queued", release.tag);
+            false
+        }
+        ReleaseState::Todo => {
+            info!("{} hasn't been queued for building", release.tag);
+            true
+        }
+    }
 }
 
 rpc_impl_struct! {
@@ -156,5 +649,47 @@ rpc_impl_struct! {
         pub fn greetings(&self, app: String) {
             info!("received greetings from {}", app);
         }
+
+        #[rpc(notification, name = "deploy:result")]
+        pub fn deploy_result(&self, target: String, _release: i32, error: Option<String>) {
+            if let Some(err) = error {
+                error!("{}: failed to deploy: {}", target, err);
+            } else {
+                info!("{}: deployed", target);
+            }
+        }
+
+        #[rpc(notification, name = "deploy:done")]
+        pub fn deploy_done(&self) {
+            info!("done");
+            if let Err(err) = self.sender.close(ws::CloseCode::Normal) {
+                error!("failed to close connection: {:?}", err);
+            }
+        }
+
+        #[rpc(notification, name = "releases:log")]
+        pub fn releases_log(&self, log: models::BuildLog) {
+            print_log(&log);
+        }
+
+        #[rpc(notification, name = "clients:presence")]
+        pub fn clients_presence(&self, client: models::Client) {
+            info!(
+                "{} {} {} ({})",
+                client_kind(&client),
+                client.name,
+                if client.connected { "connected" } else { "disconnected" },
+                client.app
+            );
+        }
+
+        #[rpc(notification, name = "releases:state")]
+        pub fn releases_state(&self, release: models::Release) {
+            if report_state(&release) {
+                if let Err(err) = self.sender.close(ws::CloseCode::Normal) {
+                    error!("failed to close connection: {:?}", err);
+                }
+            }
+        }
     }
 }
@@ -1,9 +1,21 @@
+use backoff::Backoff;
 use clap::ArgMatches;
+use log::{debug, error, info, warn};
+use openssl::ssl::SslConnector;
 use serde_derive::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
 
 mod args;
+mod backoff;
 pub mod command;
 mod socket;
+pub mod storage;
 pub mod target;
 
 pub use args::arguments;
@@ -18,13 +30,57 @@ pub enum Kind {
     Command,
 }
 
-pub fn init(args: &ArgMatches) -> (String, String, Vec<String>) {
+/// How a client gets into the castle.
+#[derive(Clone)]
+pub struct Access {
+    /// Identity the castle recognises the client by across reconnects, and across restarts unless
+    /// it couldn't be kept (see `identity`)
+    pub identity: Uuid,
+
+    /// Access token sent in the handshake
+    pub token: Option<String>,
+
+    /// TLS settings, if connecting over TLS
+    pub tls: Option<SslConnector>,
+}
+
+pub fn init(args: &ArgMatches) -> (String, Access, String, Vec<String>) {
     let verbosity = args.occurrences_of("v") as i8 - args.occurrences_of("q") as i8;
     crate::init_with_level(verbosity - 1);
 
     let host = args.value_of("host").expect("bad --host option");
     let port = args.value_of("port").expect("bad --port option");
-    let server = format!("ws://{}:{}", host, port);
+
+    let tls = if args.is_present("tls") || args.is_present("ca") || args.is_present("cert") {
+        let identity = args.value_of("cert").map(|cert| {
+            (
+                Path::new(cert),
+                Path::new(args.value_of("key").expect("bad --key option")),
+            )
+        });
+
+        Some(
+            crate::tls::connector(args.value_of("ca").map(Path::new), identity)
+                .expect("failed to set up TLS"),
+        )
+    } else {
+        None
+    };
+
+    let server = format!(
+        "{}://{}:{}",
+        if tls.is_some() { "wss" } else { "ws" },
+        host,
+        port
+    );
+    let access = Access {
+        identity: args.value_of("identity").map_or_else(
+            || identity(args),
+            |id| id.parse().expect("bad --identity option"),
+        ),
+        token: args.value_of("token").map(String::from),
+        tls,
+    };
 
     let name: String = args.value_of("name").expect("bad --name option").into();
     let tags: Vec<String> = args
@@ -32,5 +88,105 @@ pub fn init(args: &ArgMatches) -> (String, String, Vec<String>) {
         .map(|ts| ts.map(|s| s.to_string()).collect())
         .unwrap_or(Vec::new());
 
-    (server, name, tags)
+    (server, access, name, tags)
+}
+
+/// The client's identity, generated on first start and kept from then on.
+///
+/// Targets keep it in their store, the command client in the user's config directory. If there's
+/// nowhere to keep it, a new one is used every time.
+fn identity(args: &ArgMatches) -> Uuid {
+    match identity_path(args) {
+        Some(path) => load_identity(&path).expect("failed to keep client identity"),
+        None => {
+            debug!("nowhere to keep the client identity, using a new one");
+            Uuid::new_v4()
+        }
+    }
+}
+
+fn identity_path(args: &ArgMatches) -> Option<PathBuf> {
+    if let Some(store) = args.value_of("store") {
+        return Some(Path::new(store).join("identity"));
+    }
+
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|config| config.join("trebuchet").join("identity"))
+}
+
+/// Reads the identity kept at a path, or generates and keeps one there if there's none yet.
+fn load_identity(path: &Path) -> Result<Uuid> {
+    match fs::read_to_string(path) {
+        Ok(id) => id.trim().parse().map_err(|err| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("bad identity in {:?}: {}", path, err),
+            )
+        }),
+        Err(ref err) if err.kind() == ErrorKind::NotFound => {
+            let id = Uuid::new_v4();
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            // written aside then moved in place, so it's never found half-written
+            let next = path.with_extension("next");
+            fs::write(&next, format!("{}\n", id))?;
+            fs::rename(&next, path)?;
+
+            info!("generated identity {}, kept in {:?}", id, path);
+            Ok(id)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Connects to the castle, and reconnects whenever the connection drops, forever.
+///
+/// The factory is called anew for each connection.
+pub fn reconnect<F, H>(server: &str, mut factory: F)
+where
+    F: FnMut(ws::Sender) -> H,
+    H: ws::Handler,
+{
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+    loop {
+        let start = Instant::now();
+        if let Err(err) = ws::connect(server, &mut factory) {
+            error!("connection to castle failed: {}", err);
+        }
+
+        let delay = backoff.next_delay_after(start.elapsed());
+        warn!("disconnected from castle, reconnecting in {:?}", delay);
+        thread::sleep(delay);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn identity_is_kept() {
+        let dir = TempDir::new().expect("tempdir");
+        let path = dir.path().join("nested").join("identity");
+
+        let first = load_identity(&path).unwrap();
+        assert!(path.exists());
+        assert_eq!(load_identity(&path).unwrap(), first);
+        assert_eq!(fs::read_to_string(&path).unwrap().trim(), first.to_string());
+    }
+
+    #[test]
+    fn bad_identity_is_refused() {
+        let dir = TempDir::new().expect("tempdir");
+        let path = dir.path().join("identity");
+        fs::write(&path, "not a uuid").unwrap();
+
+        let err = load_identity(&path).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
 }
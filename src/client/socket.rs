@@ -1,9 +1,14 @@
-use super::Kind;
+use super::{Access, Kind};
+use crate::api::{self, Greeting};
+use crate::heartbeat::{self, Heartbeat};
 use crate::inflight::Inflight;
 use crate::rpc::{param_list, RpcClient, RpcDelegate, RpcHandler, RpcRemote};
+use crate::tls;
 use jsonrpc_core::{IoHandler, Value};
-use log::{debug, error, info};
-use serde_json::json;
+use log::{debug, error, info, warn};
+use mio::tcp::TcpStream;
+use openssl::ssl::SslStream;
+use serde_json::{from_value, json};
 use std::thread;
 
 /// Client from Worker to Agent.
@@ -14,9 +19,11 @@ where
     sender: ws::Sender,
     inflight: Inflight,
     rpc: IoHandler,
+    access: Access,
     kind: Kind,
     name: String,
     tags: Vec<String>,
+    heartbeat: Heartbeat,
     thread: Option<Box<F>>,
 }
 
@@ -24,6 +31,7 @@ impl<F: FnMut(RpcRemote) + Send + 'static> Client<F> {
     pub fn create<R>(
         rpcd: R,
         sender: ws::Sender,
+        access: Access,
         kind: Kind,
         name: String,
         tags: Vec<String>,
@@ -39,9 +47,11 @@ impl<F: FnMut(RpcRemote) + Send + 'static> Client<F> {
             sender,
             inflight: Inflight::default(),
             rpc,
+            access,
             kind,
             name,
             tags,
+            heartbeat: Heartbeat::default(),
             thread: Some(Box::new(thread)),
         }
     }
@@ -67,49 +77,125 @@ impl<F: FnMut(RpcRemote) + Send + 'static> RpcHandler for Client<F> {
 
 impl<F: FnMut(RpcRemote) + Send + 'static> ws::Handler for Client<F> {
     fn build_request(&mut self, url: &url::Url) -> ws::Result<ws::Request> {
-        self.rpc_build_request(url)
+        let mut req = self.rpc_build_request(url)?;
+        if let Some(ref token) = self.access.token {
+            req.headers_mut().push((
+                "Authorization".into(),
+                format!("Bearer {}", token).into_bytes(),
+            ));
+        } else {
+            warn!("no access token set, the castle will likely refuse the connection");
+        }
+
+        Ok(req)
+    }
+
+    fn upgrade_ssl_client(
+        &mut self,
+        sock: TcpStream,
+        url: &url::Url,
+    ) -> ws::Result<SslStream<TcpStream>> {
+        let connector = self.access.tls.as_ref().ok_or_else(|| {
+            ws::Error::new(ws::ErrorKind::Internal, "client is not set up for TLS")
+        })?;
+
+        tls::connect(connector, sock, url)
     }
 
     fn on_response(&mut self, res: &ws::Response) -> ws::Result<()> {
+        if res.status() == 401 {
+            error!("castle refused our access token");
+            return Err(ws::Error::new(ws::ErrorKind::Protocol, "unauthorized"));
+        }
+
         self.rpc_on_response(res)
     }
 
     fn on_open(&mut self, _shake: ws::Handshake) -> ws::Result<()> {
         info!("connected to castle");
+        self.heartbeat.start(&self.sender)?;
 
-        self.notify(
+        // The body only starts once the castle has accepted our greetings
+        let remote = self.remote();
+        let mut body = self.thread.take();
+        self.call(
             "greetings",
             param_list(vec![
-                Value::String(format!("Trebuchet/{}", env!("CARGO_PKG_VERSION"))),
+                Value::String(api::app_version()),
                 json!(self.kind),
                 Value::String(self.name.clone()),
                 json!(self.tags),
+                json!(self.access.identity),
             ]),
-        )?;
+            move |res| {
+                let greeting: Greeting = match res {
+                    Ok(value) => from_value(value)?,
+                    Err(err) => {
+                        error!("castle refused greetings: {}", err.message);
+                        remote.kill(None)?;
+                        return Ok(());
+                    }
+                };
 
-        let remote = self.remote();
-        let mut body = None;
-        std::mem::swap(&mut self.thread, &mut body);
-        thread::Builder::new()
-            .name("client body".into())
-            .spawn(move || {
-                debug!("client body thread start");
-                if let Some(mut body) = body {
-                    body(remote);
-                } else {
-                    error!("client body swap failed");
-                }
-                debug!("client body thread end");
-            })
-            .expect("failed to start client body");
-
-        Ok(())
+                info!(
+                    "greeted by {} as connection {}",
+                    greeting.version, greeting.connection
+                );
+                debug!("castle capabilities: {:?}", greeting.capabilities);
+
+                let body = match body.take() {
+                    Some(body) => body,
+                    None => {
+                        error!("client body already started");
+                        return Ok(());
+                    }
+                };
+
+                let remote = remote.clone();
+                thread::Builder::new()
+                    .name("client body".into())
+                    .spawn(move || {
+                        debug!("client body thread start");
+                        let mut body = body;
+                        body(remote);
+                        debug!("client body thread end");
+                    })
+                    .expect("failed to start client body");
+
+                Ok(())
+            },
+        )
     }
 
     fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
         self.rpc_on_message(msg)
     }
 
+    fn on_frame(&mut self, frame: ws::Frame) -> ws::Result<Option<ws::Frame>> {
+        self.heartbeat.seen();
+        Ok(Some(frame))
+    }
+
+    fn on_timeout(&mut self, event: ws::util::Token) -> ws::Result<()> {
+        if event != heartbeat::BEAT || self.heartbeat.beat(&self.sender)? {
+            return Ok(());
+        }
+
+        // The castle may never answer a close, so don't wait for it
+        error!("castle stopped responding, dropping the connection");
+        self.sender.shutdown()
+    }
+
+    fn on_close(&mut self, code: ws::CloseCode, reason: &str) {
+        if code == api::INCOMPATIBLE {
+            error!("castle does not support this client version: {}", reason);
+        } else if code == api::UNAUTHORIZED {
+            error!("castle refused our access token: {}", reason);
+        }
+
+        self.rpc_on_close(code, reason)
+    }
+
     fn on_shutdown(&mut self) {
         self.rpc_on_shutdown()
     }
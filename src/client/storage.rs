@@ -0,0 +1,577 @@
+//! Release storage on target hosts.
+//!
+//! Each app gets a directory under the store root, laid out such:
+//!
+//!  - `releases/<tag>@<checksum>`: writable volume a release is unpacked into,
+//!  - `snapshots/<tag>@<checksum>`: read-only snapshot of the above, taken once it's complete,
+//!  - `current`: symlink to the snapshot of the active release,
+//!  - `downloads/<release>.tar`: artefact being transferred from the castle.
+//!
+//! Releases are stored by tag and by the checksum of their artefact, as a tag can be rebuilt into
+//! a different artefact. Activating a release (forward or back) is a matter of atomically switching
+//! the `current` link.
+
+use log::{debug, info};
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// How volumes are created, snapshotted, and deleted.
+pub trait Backend: Send {
+    fn name(&self) -> &'static str;
+    fn create(&self, path: &Path) -> Result<()>;
+    fn snapshot(&self, source: &Path, dest: &Path) -> Result<()>;
+    fn delete(&self, path: &Path) -> Result<()>;
+}
+
+/// Btrfs subvolumes and read-only snapshots.
+pub struct Btrfs;
+
+impl Btrfs {
+    /// Whether a path is on a btrfs filesystem, in a subvolume we can work with.
+    pub fn supports(path: &Path) -> bool {
+        btrfs(&["subvolume".as_ref(), "show".as_ref(), path.as_os_str()]).is_ok()
+    }
+}
+
+impl Backend for Btrfs {
+    fn name(&self) -> &'static str {
+        "btrfs"
+    }
+
+    fn create(&self, path: &Path) -> Result<()> {
+        btrfs(&["subvolume".as_ref(), "create".as_ref(), path.as_os_str()])
+    }
+
+    fn snapshot(&self, source: &Path, dest: &Path) -> Result<()> {
+        btrfs(&[
+            "subvolume".as_ref(),
+            "snapshot".as_ref(),
+            "-r".as_ref(),
+            source.as_os_str(),
+            dest.as_os_str(),
+        ])
+    }
+
+    fn delete(&self, path: &Path) -> Result<()> {
+        btrfs(&["subvolume".as_ref(), "delete".as_ref(), path.as_os_str()])
+    }
+}
+
+fn btrfs(args: &[&std::ffi::OsStr]) -> Result<()> {
+    debug!("running btrfs {:?}", args);
+    let out = Command::new("btrfs").args(args).output()?;
+    if out.status.success() {
+        Ok(())
+    } else {
+        Err(Error::new(
+            ErrorKind::Other,
+            format!(
+                "btrfs {:?} failed ({}): {}",
+                args,
+                out.status,
+                String::from_utf8_lossy(&out.stderr).trim()
+            ),
+        ))
+    }
+}
+
+/// Plain directories and copies, for any filesystem.
+pub struct Directory;
+
+impl Backend for Directory {
+    fn name(&self) -> &'static str {
+        "directory"
+    }
+
+    fn create(&self, path: &Path) -> Result<()> {
+        fs::create_dir(path)
+    }
+
+    fn snapshot(&self, source: &Path, dest: &Path) -> Result<()> {
+        copy_readonly(source, dest)
+    }
+
+    fn delete(&self, path: &Path) -> Result<()> {
+        make_writable(path)?;
+        fs::remove_dir_all(path)
+    }
+}
+
+fn copy_readonly(source: &Path, dest: &Path) -> Result<()> {
+    fs::create_dir(dest)?;
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let kind = entry.file_type()?;
+        let to = dest.join(entry.file_name());
+
+        if kind.is_dir() {
+            copy_readonly(&entry.path(), &to)?;
+        } else if kind.is_symlink() {
+            symlink(fs::read_link(entry.path())?, &to)?;
+        } else {
+            fs::copy(entry.path(), &to)?;
+            let mut perms = fs::metadata(&to)?.permissions();
+            perms.set_readonly(true);
+            fs::set_permissions(&to, perms)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn make_writable(path: &Path) -> Result<()> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let kind = entry.file_type()?;
+
+        if kind.is_dir() {
+            make_writable(&entry.path())?;
+        } else if kind.is_file() {
+            let mut perms = entry.metadata()?.permissions();
+            perms.set_readonly(false);
+            fs::set_permissions(entry.path(), perms)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// A stored release: its tag, and the checksum of the artefact it was unpacked from.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct Stored {
+    pub tag: String,
+    pub checksum: String,
+}
+
+impl Stored {
+    pub fn new(tag: &str, checksum: &str) -> Self {
+        Self {
+            tag: tag.into(),
+            checksum: checksum.into(),
+        }
+    }
+
+    /// The name the release is stored under.
+    fn key(&self) -> Result<String> {
+        if self.checksum.is_empty() || !self.checksum.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid checksum for storage: {:?}", self.checksum),
+            ));
+        }
+
+        Ok(format!("{}@{}", component(&self.tag)?, self.checksum))
+    }
+
+    /// Tags may contain `@` too, but checksums never do, so the last one splits the key.
+    fn from_key(key: &str) -> Option<Self> {
+        let mut parts = key.rsplitn(2, '@');
+        let checksum = parts.next()?;
+        let tag = parts.next()?;
+        Some(Self::new(&decode(tag), checksum))
+    }
+}
+
+pub struct Storage {
+    root: PathBuf,
+    backend: Box<dyn Backend>,
+}
+
+impl Storage {
+    pub fn new(root: PathBuf, backend: Box<dyn Backend>) -> Self {
+        info!("storing releases in {:?} using {}", root, backend.name());
+        Self { root, backend }
+    }
+
+    /// Picks the btrfs backend if the store root supports it, plain directories otherwise.
+    pub fn detect(root: PathBuf) -> Result<Self> {
+        fs::create_dir_all(&root)?;
+        let backend: Box<dyn Backend> = if Btrfs::supports(&root) {
+            Box::new(Btrfs)
+        } else {
+            Box::new(Directory)
+        };
+
+        Ok(Self::new(root, backend))
+    }
+
+    fn app_path(&self, app: &str) -> Result<PathBuf> {
+        Ok(self.root.join(component(app)?))
+    }
+
+    /// Path to the writable volume for a release.
+    pub fn volume_path(&self, app: &str, release: &Stored) -> Result<PathBuf> {
+        Ok(self.app_path(app)?.join("releases").join(release.key()?))
+    }
+
+    /// Path to the read-only snapshot of a release.
+    pub fn snapshot_path(&self, app: &str, release: &Stored) -> Result<PathBuf> {
+        Ok(self.app_path(app)?.join("snapshots").join(release.key()?))
+    }
+
+    /// Path to the (possibly partial) artefact download for a release.
+    pub fn download_path(&self, app: &str, release_id: i32) -> Result<PathBuf> {
+        Ok(self
+            .app_path(app)?
+            .join("downloads")
+            .join(format!("{}.tar", release_id)))
+    }
+
+    /// Whether a release has been stored and snapshotted, ready to be activated.
+    pub fn has(&self, app: &str, release: &Stored) -> bool {
+        self.snapshot_path(app, release)
+            .map(|path| path.exists())
+            .unwrap_or(false)
+    }
+
+    /// Creates a fresh writable volume for a release, replacing any leftover one.
+    pub fn create(&self, app: &str, release: &Stored) -> Result<PathBuf> {
+        let path = self.volume_path(app, release)?;
+        if path.exists() {
+            debug!("clearing leftover volume {:?}", path);
+            self.backend.delete(&path)?;
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        self.backend.create(&path)?;
+        Ok(path)
+    }
+
+    /// Takes a read-only snapshot of a release's volume, so it can be activated.
+    pub fn freeze(&self, app: &str, release: &Stored) -> Result<PathBuf> {
+        let source = self.volume_path(app, release)?;
+        let dest = self.snapshot_path(app, release)?;
+
+        if dest.exists() {
+            if self.current(app)?.as_ref() == Some(release) {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    "cannot replace the snapshot of the active release",
+                ));
+            }
+
+            debug!("replacing snapshot {:?}", dest);
+            self.backend.delete(&dest)?;
+        }
+
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        self.backend.snapshot(&source, &dest)?;
+        Ok(dest)
+    }
+
+    /// Switches the app's `current` link to a stored release.
+    pub fn activate(&self, app: &str, release: &Stored) -> Result<()> {
+        if !self.has(app, release) {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!(
+                    "release {} ({}) of {} is not stored",
+                    release.tag, release.checksum, app
+                ),
+            ));
+        }
+
+        let app_path = self.app_path(app)?;
+        let target = Path::new("snapshots").join(release.key()?);
+        let link = app_path.join("current");
+        let next = app_path.join(".current.next");
+
+        if next.symlink_metadata().is_ok() {
+            fs::remove_file(&next)?;
+        }
+
+        // Renaming over the old link is atomic, so there's always a valid `current`
+        symlink(&target, &next)?;
+        fs::rename(&next, &link)?;
+
+        info!("activated {} {} ({})", app, release.tag, release.checksum);
+        Ok(())
+    }
+
+    /// The app's active release, if any.
+    pub fn current(&self, app: &str) -> Result<Option<Stored>> {
+        let link = self.app_path(app)?.join("current");
+        match fs::read_link(&link) {
+            Ok(target) => Ok(target
+                .file_name()
+                .and_then(|name| Stored::from_key(&name.to_string_lossy()))),
+            Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// All the app's stored releases.
+    pub fn list(&self, app: &str) -> Result<Vec<Stored>> {
+        let dir = self.app_path(app)?.join("snapshots");
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut releases = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            if let Some(release) = Stored::from_key(&entry?.file_name().to_string_lossy()) {
+                releases.push(release);
+            }
+        }
+
+        releases.sort();
+        Ok(releases)
+    }
+
+    /// Deletes a stored release, unless it's the active one.
+    pub fn remove(&self, app: &str, release: &Stored) -> Result<()> {
+        if self.current(app)?.as_ref() == Some(release) {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "cannot remove the active release",
+            ));
+        }
+
+        for path in &[
+            self.snapshot_path(app, release)?,
+            self.volume_path(app, release)?,
+        ] {
+            if path.exists() {
+                self.backend.delete(path)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Makes a name safe to use as a single path component.
+///
+/// Git tags may contain slashes, so those are escaped rather than refused.
+fn component(name: &str) -> Result<String> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid name for storage: {:?}", name),
+        ));
+    }
+
+    Ok(name.replace('%', "%25").replace('/', "%2F"))
+}
+
+fn decode(component: &str) -> String {
+    component.replace("%2F", "/").replace("%25", "%")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn storage() -> (TempDir, Storage) {
+        let root = TempDir::new().expect("tempdir");
+        let storage = Storage::new(root.path().join("store"), Box::new(Directory));
+        (root, storage)
+    }
+
+    fn release(tag: &str) -> Stored {
+        Stored::new(tag, "abc123")
+    }
+
+    fn store(storage: &Storage, release: &Stored, contents: &str) {
+        let volume = storage.create("app", release).expect("create");
+        fs::write(volume.join("version"), contents).expect("write");
+        storage.freeze("app", release).expect("freeze");
+    }
+
+    fn active_contents(storage: &Storage) -> String {
+        let current = storage.app_path("app").unwrap().join("current");
+        fs::read_to_string(current.join("version")).expect("read through current")
+    }
+
+    #[test]
+    fn component_escapes_slashes() {
+        assert_eq!(component("v1.0").unwrap(), "v1.0");
+        assert_eq!(component("feature/x").unwrap(), "feature%2Fx");
+        assert_eq!(component("100%/done").unwrap(), "100%25%2Fdone");
+        assert!(component("").is_err());
+        assert!(component(".").is_err());
+        assert!(component("..").is_err());
+    }
+
+    #[test]
+    fn decode_reverses_component() {
+        for name in &["v1.0", "feature/x", "a/b/c", "100%", "%2F", "lit%2F/tag"] {
+            assert_eq!(&decode(&component(name).unwrap()), name);
+        }
+    }
+
+    #[test]
+    fn keys_round_trip() {
+        for tag in &["v1.0", "feature/x", "user@host", "a@b/c@d"] {
+            let release = Stored::new(tag, "0123456789abcdef");
+            assert_eq!(Stored::from_key(&release.key().unwrap()), Some(release));
+        }
+
+        assert!(Stored::new("v1", "").key().is_err());
+        assert!(Stored::new("v1", "../../etc").key().is_err());
+        assert!(Stored::new("v1", "ab@cd").key().is_err());
+        assert_eq!(Stored::from_key("no-checksum"), None);
+    }
+
+    #[test]
+    fn create_replaces_leftover_volume() {
+        let (_root, storage) = storage();
+        let volume = storage.create("app", &release("v1")).unwrap();
+        fs::write(volume.join("leftover"), "").unwrap();
+
+        let volume = storage.create("app", &release("v1")).unwrap();
+        assert!(volume.is_dir());
+        assert!(!volume.join("leftover").exists());
+    }
+
+    #[test]
+    fn freeze_makes_readonly_snapshot() {
+        let (_root, storage) = storage();
+        assert!(!storage.has("app", &release("v1")));
+        store(&storage, &release("v1"), "one");
+
+        assert!(storage.has("app", &release("v1")));
+        let file = storage
+            .snapshot_path("app", &release("v1"))
+            .unwrap()
+            .join("version");
+        assert_eq!(fs::read_to_string(&file).unwrap(), "one");
+        assert!(fs::metadata(&file).unwrap().permissions().readonly());
+    }
+
+    #[test]
+    fn activate_switches_forward_and_back() {
+        let (_root, storage) = storage();
+        assert_eq!(storage.current("app").unwrap(), None);
+
+        store(&storage, &release("v1"), "one");
+        store(&storage, &release("v2"), "two");
+
+        storage.activate("app", &release("v1")).unwrap();
+        assert_eq!(storage.current("app").unwrap(), Some(release("v1")));
+        assert_eq!(active_contents(&storage), "one");
+
+        storage.activate("app", &release("v2")).unwrap();
+        assert_eq!(storage.current("app").unwrap(), Some(release("v2")));
+        assert_eq!(active_contents(&storage), "two");
+
+        storage.activate("app", &release("v1")).unwrap();
+        assert_eq!(storage.current("app").unwrap(), Some(release("v1")));
+        assert_eq!(active_contents(&storage), "one");
+    }
+
+    #[test]
+    fn rebuilt_tags_are_stored_apart() {
+        let (_root, storage) = storage();
+        let built = Stored::new("v1", "aaaa");
+        let rebuilt = Stored::new("v1", "bbbb");
+
+        store(&storage, &built, "built");
+        storage.activate("app", &built).unwrap();
+        assert!(!storage.has("app", &rebuilt));
+
+        store(&storage, &rebuilt, "rebuilt");
+        storage.activate("app", &rebuilt).unwrap();
+        assert_eq!(storage.current("app").unwrap(), Some(rebuilt.clone()));
+        assert_eq!(active_contents(&storage), "rebuilt");
+        assert_eq!(storage.list("app").unwrap(), vec![built, rebuilt]);
+    }
+
+    #[test]
+    fn activate_refuses_unstored_release() {
+        let (_root, storage) = storage();
+        store(&storage, &release("v1"), "one");
+        storage.activate("app", &release("v1")).unwrap();
+
+        let err = storage.activate("app", &release("v2")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert_eq!(storage.current("app").unwrap(), Some(release("v1")));
+    }
+
+    #[test]
+    fn list_is_sorted_and_decoded() {
+        let (_root, storage) = storage();
+        assert!(storage.list("app").unwrap().is_empty());
+
+        store(&storage, &release("v2"), "two");
+        store(&storage, &release("feature/x"), "x");
+        store(&storage, &release("v1"), "one");
+
+        assert_eq!(
+            storage.list("app").unwrap(),
+            vec![release("feature/x"), release("v1"), release("v2")]
+        );
+    }
+
+    #[test]
+    fn slashed_tags_round_trip() {
+        let (_root, storage) = storage();
+        let slashed = release("release/2019-04");
+        store(&storage, &slashed, "slashed");
+
+        let snapshots = storage.app_path("app").unwrap().join("snapshots");
+        assert!(snapshots.join("release%2F2019-04@abc123").is_dir());
+        assert!(!snapshots.join("release").exists());
+
+        storage.activate("app", &slashed).unwrap();
+        assert_eq!(storage.current("app").unwrap(), Some(slashed));
+        assert_eq!(active_contents(&storage), "slashed");
+    }
+
+    #[test]
+    fn remove_deletes_snapshot_and_volume() {
+        let (_root, storage) = storage();
+        store(&storage, &release("v1"), "one");
+        store(&storage, &release("v2"), "two");
+        storage.activate("app", &release("v2")).unwrap();
+
+        storage.remove("app", &release("v1")).unwrap();
+        assert!(!storage.has("app", &release("v1")));
+        assert!(!storage.volume_path("app", &release("v1")).unwrap().exists());
+        assert_eq!(storage.list("app").unwrap(), vec![release("v2")]);
+
+        // Removing something that isn't there is fine
+        storage.remove("app", &release("v1")).unwrap();
+    }
+
+    #[test]
+    fn remove_refuses_active_release() {
+        let (_root, storage) = storage();
+        store(&storage, &release("v1"), "one");
+        storage.activate("app", &release("v1")).unwrap();
+
+        assert!(storage.remove("app", &release("v1")).is_err());
+        assert!(storage.has("app", &release("v1")));
+        assert_eq!(active_contents(&storage), "one");
+    }
+
+    #[test]
+    fn freeze_refuses_to_replace_active_release() {
+        let (_root, storage) = storage();
+        store(&storage, &release("v1"), "one");
+        storage.activate("app", &release("v1")).unwrap();
+
+        let volume = storage.create("app", &release("v1")).unwrap();
+        fs::write(volume.join("version"), "changed").unwrap();
+        assert!(storage.freeze("app", &release("v1")).is_err());
+        assert_eq!(active_contents(&storage), "one");
+
+        // But an inactive one can be refrozen
+        store(&storage, &release("v2"), "two");
+        let volume = storage.create("app", &release("v2")).unwrap();
+        fs::write(volume.join("version"), "again").unwrap();
+        storage.freeze("app", &release("v2")).unwrap();
+        storage.activate("app", &release("v2")).unwrap();
+        assert_eq!(active_contents(&storage), "again");
+    }
+}
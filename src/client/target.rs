@@ -1,17 +1,291 @@
-use crate::rpc::{RpcDelegate, RpcRemote};
-use clap::{App, ArgMatches};
-use jsonrpc_core::Metadata;
+use super::storage::{self, Storage, Stored};
+use crate::rpc::{app_error, param_list, RpcClient, RpcDelegate, RpcRemote};
+use clap::{App, Arg, ArgMatches};
+use crossbeam_channel::{Receiver, Sender};
+use jsonrpc_core::{
+    futures::{future, sync::oneshot, Future},
+    BoxFuture, Metadata, Params, Result as RpcResult, Value,
+};
 use jsonrpc_macros::IoDelegate;
-use log::info;
+use log::{debug, error, info, warn};
 use rpc_impl_macro::{rpc, rpc_impl_struct};
+use serde_derive::Deserialize;
+use serde_json::{from_value, json};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error, ErrorKind, Result, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
 pub fn arguments<'a, 'b>() -> App<'a, 'b> {
-    super::arguments("Trebuchet target client").bin_name("trebuchet-target")
+    super::arguments("Trebuchet target client")
+        .bin_name("trebuchet-target")
+        .arg(
+            Arg::with_name("store")
+                .long("store")
+                .value_name("DIR")
+                .help("Sets where releases are stored")
+                .takes_value(true)
+                .default_value("/var/lib/trebuchet"),
+        )
+        .arg(
+            Arg::with_name("backend")
+                .long("backend")
+                .value_name("BACKEND")
+                .help("Sets how releases are stored")
+                .takes_value(true)
+                .possible_values(&["auto", "btrfs", "directory"])
+                .default_value("auto"),
+        )
 }
 
-pub fn handler(_remote: RpcRemote, _args: ArgMatches) {}
+/// Work handed from the RPC handlers to the client body.
+///
+/// RPC handlers run on the websocket thread, so anything that needs to talk back to the castle
+/// must happen elsewhere, or it will wait forever on responses that can't be received.
+#[derive(Debug)]
+pub enum Job {
+    Deploy {
+        app: String,
+        tag: String,
+        release: i32,
+        checksum: String,
+        done: oneshot::Sender<RpcResult<Value>>,
+    },
+}
+
+pub fn handler(remote: RpcRemote, args: ArgMatches, jobs: Receiver<Job>) {
+    let root = PathBuf::from(args.value_of("store").expect("bad --store option"));
+    let storage = match args.value_of("backend") {
+        Some("btrfs") => Ok(Storage::new(root, Box::new(storage::Btrfs))),
+        Some("directory") => Ok(Storage::new(root, Box::new(storage::Directory))),
+        _ => Storage::detect(root),
+    }
+    .expect("failed to set up release storage");
+
+    for job in jobs.iter() {
+        match job {
+            Job::Deploy {
+                app,
+                tag,
+                release,
+                checksum,
+                done,
+            } => {
+                info!("deploying {} {}", app, tag);
+                let stored = Stored::new(&tag, &checksum);
+                let res = deploy(&remote, &storage, &app, &stored, release)
+                    .map(|()| json!(true))
+                    .map_err(|err| {
+                        error!("failed to deploy {} {}: {}", app, tag, err);
+                        app_error(500, &err.to_string(), None)
+                    });
+
+                if done.send(res).is_err() {
+                    warn!("deploy of {} {} outlived the call for it", app, tag);
+                }
+            }
+        }
+    }
+}
+
+/// Stores a release if it isn't already, and activates it.
+///
+/// Releases are stored by the checksum of their artefact, so a rebuilt tag is fetched again.
+fn deploy(
+    remote: &RpcRemote,
+    storage: &Storage,
+    app: &str,
+    stored: &Stored,
+    release: i32,
+) -> Result<()> {
+    if !storage.has(app, stored) {
+        let download = fetch(remote, storage, app, release)?;
+        let fetched = fs::read_to_string(download.with_extension("sha256"))?;
+        if fetched != stored.checksum {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "artefact changed since the deploy was asked for: {} != {}",
+                    fetched, stored.checksum
+                ),
+            ));
+        }
+
+        let volume = storage.create(app, stored)?;
+        unpack(&download, &volume)?;
+        storage.freeze(app, stored)?;
+
+        fs::remove_file(&download)?;
+        fs::remove_file(download.with_extension("sha256"))?;
+    }
+
+    storage.activate(app, stored)
+}
 
-pub struct Rpc;
+/// One chunk of a release artefact, as sent by the castle.
+#[derive(Debug, Deserialize)]
+struct Chunk {
+    offset: u64,
+    size: u64,
+    checksum: String,
+    #[serde(rename = ".raw")]
+    raw: Vec<Vec<u8>>,
+}
+
+/// Downloads a release artefact from the castle, resuming any previous partial transfer.
+///
+/// Everything written to the download file has been confirmed by the castle, so after a reconnect
+/// the transfer picks up from the file's length. The checksum of the artefact is kept alongside:
+/// if the castle reports a different one (e.g. the release was rebuilt), the transfer restarts.
+fn fetch(remote: &RpcRemote, storage: &Storage, app: &str, release: i32) -> Result<PathBuf> {
+    let path = storage.download_path(app, release)?;
+    let sum_path = path.with_extension("sha256");
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    let mut offset = file.metadata()?.len();
+    let mut expected = fs::read_to_string(&sum_path).ok();
+    if offset > 0 {
+        info!(
+            "resuming transfer of release {} from {} bytes",
+            release, offset
+        );
+    }
+
+    loop {
+        let chunk: Chunk = from_value(call_wait(
+            remote,
+            "artefacts:read",
+            param_list(vec![json!(release), json!(offset)]),
+        )?)
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+
+        if expected.as_ref() != Some(&chunk.checksum) {
+            if offset > 0 {
+                warn!(
+                    "artefact for release {} changed, restarting transfer",
+                    release
+                );
+                file.set_len(0)?;
+                offset = 0;
+                expected = None;
+                continue;
+            }
+
+            fs::write(&sum_path, &chunk.checksum)?;
+            expected = Some(chunk.checksum.clone());
+        }
+
+        if chunk.offset != offset {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("expected chunk at {}, got {}", offset, chunk.offset),
+            ));
+        }
+
+        for data in &chunk.raw {
+            file.write_all(data)?;
+            offset += data.len() as u64;
+        }
+
+        file.sync_data()?;
+        debug!("got {}/{} bytes of release {}", offset, chunk.size, release);
+
+        if offset >= chunk.size {
+            break;
+        }
+
+        if chunk.raw.iter().all(Vec::is_empty) {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "castle sent an empty chunk before the end",
+            ));
+        }
+    }
+
+    let sum = crate::checksum(File::open(&path)?)?;
+    if Some(&sum) != expected.as_ref() {
+        fs::remove_file(&path)?;
+        fs::remove_file(&sum_path)?;
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("artefact checksum mismatch: {} != {:?}", sum, expected),
+        ));
+    }
+
+    info!("transferred release {} ({} bytes)", release, offset);
+    Ok(path)
+}
+
+fn unpack(artefact: &Path, dest: &Path) -> Result<()> {
+    let status = Command::new("tar")
+        .arg("--extract")
+        .arg("--file")
+        .arg(artefact)
+        .arg("--directory")
+        .arg(dest)
+        .status()?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(Error::new(
+            ErrorKind::Other,
+            format!("failed to unpack artefact: tar {}", status),
+        ))
+    }
+}
+
+/// Calls the castle and waits for the response.
+///
+/// Only use this off the websocket thread, or it will wait until the call times out.
+fn call_wait(remote: &RpcRemote, method: &str, params: Params) -> Result<Value> {
+    remote
+        .call_async(method, params, &[])
+        .map_err(|err| Error::new(ErrorKind::Other, err.to_string()))?
+        .wait()
+        .map_err(|err| Error::new(ErrorKind::Other, format!("{}: {}", method, err.message)))
+}
+
+pub struct Rpc {
+    jobs: Sender<Job>,
+}
+
+impl Rpc {
+    pub fn new(jobs: Sender<Job>) -> Self {
+        Self { jobs }
+    }
+
+    /// Queues a deploy, and answers the castle once it's done.
+    fn release_deploy(&self, params: Params) -> BoxFuture<Value> {
+        let (app, tag, release, checksum): (String, String, i32, String) = match params.parse() {
+            Ok(params) => params,
+            Err(err) => return Box::new(future::err(err)),
+        };
+
+        info!("queueing deploy of {} {}", app, tag);
+        let (done, res) = oneshot::channel();
+        let job = Job::Deploy {
+            app,
+            tag,
+            release,
+            checksum,
+            done,
+        };
+
+        if self.jobs.send(job).is_err() {
+            return Box::new(future::err(disconnected()));
+        }
+
+        Box::new(res.then(|res| res.unwrap_or_else(|_| Err(disconnected()))))
+    }
+}
+
+fn disconnected() -> jsonrpc_core::Error {
+    app_error(68, "job channel disconnected", None)
+}
 
 rpc_impl_struct! {
     impl Rpc {
@@ -28,6 +302,13 @@ impl RpcDelegate for Rpc {
         M: Metadata,
         Self: Sized + Send + Sync,
     {
-        self.to_delegate()
+        let mut delegate = self.to_delegate();
+
+        // deploys take a while, so this answers later rather than hold up the websocket thread
+        delegate.add_method("release:deploy", |rpc: &Self, params: Params| {
+            rpc.release_deploy(params)
+        });
+
+        delegate
     }
 }
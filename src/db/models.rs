@@ -1,9 +1,10 @@
-use super::schema::{apps, clients, releases};
+use super::schema::{apps, build_logs, clients, deployments, releases, tokens};
+use super::types::{LogStream, ReleaseState, Role};
 use chrono::{DateTime, Utc};
 use serde_derive::{Deserialize, Serialize};
 use uuid::Uuid;
 
-#[derive(Clone, Debug, Queryable)]
+#[derive(Clone, Debug, Deserialize, Queryable, Serialize)]
 pub struct Client {
     pub id: i32,
     pub connection: Uuid,
@@ -13,13 +14,18 @@ pub struct Client {
     pub target: bool,
     pub app: String,
     pub name: String,
-    pub tags: Vec<String>,
+    pub tags: Option<Vec<String>>,
+    pub identity: Option<Uuid>,
+    pub boot: Option<Uuid>,
 }
 
 #[derive(AsChangeset, Clone, Debug, Insertable)]
 #[table_name = "clients"]
 pub struct NewClient {
+    pub identity: Uuid,
     pub connection: Uuid,
+    pub connected: bool,
+    pub boot: Uuid,
     pub target: bool,
     pub app: String,
     pub name: String,
@@ -34,6 +40,7 @@ pub struct App {
     pub updated: DateTime<Utc>,
     pub repo: String,
     pub build_script: String,
+    pub archived: Option<DateTime<Utc>>,
 }
 
 #[derive(AsChangeset, Clone, Debug, Insertable)]
@@ -47,17 +54,97 @@ pub struct NewApp {
 #[derive(Clone, Debug, Deserialize, Queryable, Serialize)]
 pub struct Release {
     pub id: i32,
+    pub app_id: Option<i32>,
     pub tag: String,
     pub created: DateTime<Utc>,
     pub updated: DateTime<Utc>,
     pub repo: String,
     pub build_script: String,
+    pub state: ReleaseState,
+    pub build_started: Option<DateTime<Utc>>,
+    pub build_finished: Option<DateTime<Utc>>,
+    pub build_status: Option<i32>,
+    pub archived: Option<DateTime<Utc>>,
 }
 
 #[derive(AsChangeset, Clone, Debug, Insertable)]
 #[table_name = "releases"]
 pub struct NewRelease {
+    pub app_id: i32,
     pub tag: String,
     pub repo: String,
     pub build_script: String,
 }
+
+#[derive(AsChangeset, Clone, Debug)]
+#[table_name = "releases"]
+#[changeset_options(treat_none_as_null = "true")]
+pub struct ReleaseBuild {
+    pub state: ReleaseState,
+    pub build_started: Option<DateTime<Utc>>,
+    pub build_finished: Option<DateTime<Utc>>,
+    pub build_status: Option<i32>,
+}
+
+#[derive(Clone, Debug, Deserialize, Queryable, Serialize)]
+pub struct BuildLog {
+    pub id: i32,
+    pub release_id: i32,
+    pub created: DateTime<Utc>,
+    pub stream: LogStream,
+    pub line: String,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[table_name = "build_logs"]
+pub struct NewBuildLog {
+    pub release_id: i32,
+    pub stream: LogStream,
+    pub line: String,
+}
+
+/// A release along with its build output so far.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ReleaseLogs {
+    pub release: Release,
+    pub logs: Vec<BuildLog>,
+}
+
+#[derive(Clone, Debug, Deserialize, Queryable, Serialize)]
+pub struct Deployment {
+    pub id: i32,
+    pub client_id: i32,
+    pub release_id: i32,
+    pub created: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+    pub active: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[table_name = "deployments"]
+pub struct NewDeployment {
+    pub client_id: i32,
+    pub release_id: i32,
+    pub active: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, Queryable)]
+pub struct Token {
+    pub id: i32,
+    pub name: String,
+    pub created: DateTime<Utc>,
+    pub updated: DateTime<Utc>,
+    pub hash: String,
+    pub role: Role,
+    pub revoked: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Debug, Insertable)]
+#[table_name = "tokens"]
+pub struct NewToken {
+    pub name: String,
+    pub hash: String,
+    pub role: Role,
+}
@@ -6,6 +6,19 @@ table! {
         updated -> Timestamptz,
         repo -> Text,
         build_script -> Text,
+        archived -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::db::types::Log_stream;
+    build_logs (id) {
+        id -> Int4,
+        release_id -> Int4,
+        created -> Timestamptz,
+        stream -> Log_stream,
+        line -> Text,
     }
 }
 
@@ -20,6 +33,20 @@ table! {
         app -> Text,
         name -> Text,
         tags -> Nullable<Array<Text>>,
+        identity -> Nullable<Uuid>,
+        boot -> Nullable<Uuid>,
+    }
+}
+
+table! {
+    deployments (id) {
+        id -> Int4,
+        client_id -> Int4,
+        release_id -> Int4,
+        created -> Timestamptz,
+        updated -> Timestamptz,
+        active -> Bool,
+        error -> Nullable<Text>,
     }
 }
 
@@ -35,9 +62,30 @@ table! {
         repo -> Text,
         build_script -> Text,
         state -> Release_state,
+        build_started -> Nullable<Timestamptz>,
+        build_finished -> Nullable<Timestamptz>,
+        build_status -> Nullable<Int4>,
+        archived -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    use diesel::sql_types::*;
+    use crate::db::types::Token_role;
+    tokens (id) {
+        id -> Int4,
+        name -> Text,
+        created -> Timestamptz,
+        updated -> Timestamptz,
+        hash -> Text,
+        role -> Token_role,
+        revoked -> Nullable<Timestamptz>,
     }
 }
 
+joinable!(build_logs -> releases (release_id));
+joinable!(deployments -> clients (client_id));
+joinable!(deployments -> releases (release_id));
 joinable!(releases -> apps (app_id));
 
-allow_tables_to_appear_in_same_query!(apps, clients, releases,);
+allow_tables_to_appear_in_same_query!(apps, build_logs, clients, deployments, releases, tokens,);
@@ -1,10 +1,46 @@
 use diesel_derive_enum::DbEnum;
 use serde_derive::{Deserialize, Serialize};
+use std::str::FromStr;
 
-#[derive(Clone, DbEnum, Debug, Deserialize, Serialize)]
+#[derive(Clone, DbEnum, Debug, Deserialize, Eq, PartialEq, Serialize)]
 #[DieselType = "Release_state"]
 pub enum ReleaseState {
     Todo,
+    Queued,
     Building,
     Ready,
+    Failed,
+}
+
+#[derive(Clone, DbEnum, Debug, Deserialize, Serialize)]
+#[DieselType = "Log_stream"]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Clone, Copy, DbEnum, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[DieselType = "Token_role"]
+pub enum Role {
+    /// Can greet as a target, fetch artefacts, and report deploys
+    Target,
+
+    /// Can list and follow, but not change anything
+    Reader,
+
+    /// Can do everything a command client can
+    Admin,
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "target" => Ok(Role::Target),
+            "reader" => Ok(Role::Reader),
+            "admin" => Ok(Role::Admin),
+            _ => Err(format!("unknown role: {}", s)),
+        }
+    }
 }
@@ -0,0 +1,162 @@
+//! A single thread driving the futures of outgoing calls, and another waking them at deadlines.
+//!
+//! Callbacks given to `RpcClient` run here, so they must not block: anything that needs to wait
+//! should be handed off to another thread.
+
+use crossbeam_channel::{self as channel, RecvTimeoutError};
+use jsonrpc_core::futures::{
+    stream::FuturesUnordered,
+    sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    task, Async, Future, Poll, Stream,
+};
+use lazy_static::lazy_static;
+use log::{debug, error};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+type Task = Box<dyn Future<Item = (), Error = ()> + Send>;
+
+lazy_static! {
+    static ref DISPATCHER: Mutex<UnboundedSender<Task>> = Mutex::new(start());
+    static ref TIMER: Mutex<channel::Sender<Wakeup>> = Mutex::new(start_timer());
+}
+
+/// Runs a future to completion on the dispatcher thread.
+pub fn spawn<F>(task: F)
+where
+    F: Future<Item = (), Error = ()> + Send + 'static,
+{
+    let sent = DISPATCHER
+        .lock()
+        .expect("dispatcher lock poisoned")
+        .unbounded_send(Box::new(task));
+
+    if sent.is_err() {
+        error!("dispatcher thread is gone, dropping task");
+    }
+}
+
+fn start() -> UnboundedSender<Task> {
+    let (tx, rx) = unbounded();
+    std::thread::Builder::new()
+        .name("rpc dispatcher".into())
+        .spawn(move || {
+            debug!("rpc dispatcher thread start");
+            Dispatcher {
+                incoming: rx,
+                tasks: FuturesUnordered::new(),
+                closed: false,
+            }
+            .wait()
+            .ok();
+            debug!("rpc dispatcher thread end");
+        })
+        .expect("failed to start rpc dispatcher");
+
+    tx
+}
+
+struct Dispatcher {
+    incoming: UnboundedReceiver<Task>,
+    tasks: FuturesUnordered<Task>,
+    closed: bool,
+}
+
+impl Future for Dispatcher {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        while !self.closed {
+            match self.incoming.poll()? {
+                Async::Ready(Some(task)) => self.tasks.push(task),
+                Async::Ready(None) => self.closed = true,
+                Async::NotReady => break,
+            }
+        }
+
+        loop {
+            match self.tasks.poll() {
+                // tasks handle their own errors, this only keeps one from stopping the rest
+                Ok(Async::Ready(Some(()))) | Err(()) => continue,
+                Ok(Async::Ready(None)) if self.closed => return Ok(Async::Ready(())),
+                Ok(Async::Ready(None)) | Ok(Async::NotReady) => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+/// Wakes a task up at a deadline, so it can notice it's been reached.
+pub fn notify_at(deadline: Instant, task: task::Task) {
+    let sent = TIMER
+        .lock()
+        .expect("timer lock poisoned")
+        .send(Wakeup { deadline, task });
+
+    if sent.is_err() {
+        error!("timer thread is gone, dropping wakeup");
+    }
+}
+
+struct Wakeup {
+    deadline: Instant,
+    task: task::Task,
+}
+
+// Ordered by soonest deadline first, for the timer's max-heap
+impl Ord for Wakeup {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.deadline.cmp(&self.deadline)
+    }
+}
+
+impl PartialOrd for Wakeup {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Wakeup {
+    fn eq(&self, other: &Self) -> bool {
+        self.deadline == other.deadline
+    }
+}
+
+impl Eq for Wakeup {}
+
+fn start_timer() -> channel::Sender<Wakeup> {
+    let (tx, rx) = channel::unbounded();
+    std::thread::Builder::new()
+        .name("rpc timer".into())
+        .spawn(move || {
+            debug!("rpc timer thread start");
+            let mut wakeups: BinaryHeap<Wakeup> = BinaryHeap::new();
+
+            loop {
+                let now = Instant::now();
+                while wakeups.peek().map_or(false, |w| w.deadline <= now) {
+                    if let Some(wakeup) = wakeups.pop() {
+                        wakeup.task.notify();
+                    }
+                }
+
+                let next = match wakeups.peek() {
+                    Some(wakeup) => rx.recv_timeout(wakeup.deadline - now),
+                    None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                };
+
+                match next {
+                    Ok(wakeup) => wakeups.push(wakeup),
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+
+            debug!("rpc timer thread end");
+        })
+        .expect("failed to start rpc timer");
+
+    tx
+}
@@ -5,6 +5,7 @@ use std::fmt::{Display, Formatter, Result as FmtResult};
 pub enum Error {
     Rpc(jsonrpc_core::Error),
     Json(serde_json::Error),
+    Ws(ws::Error),
 }
 
 impl Display for Error {
@@ -26,3 +27,9 @@ impl From<jsonrpc_core::Error> for Error {
         Error::Rpc(err)
     }
 }
+
+impl From<ws::Error> for Error {
+    fn from(err: ws::Error) -> Self {
+        Error::Ws(err)
+    }
+}
@@ -0,0 +1,67 @@
+//! Websocket heartbeats, to notice peers that have silently gone away.
+//!
+//! A half-open connection (the peer crashed, or the network dropped) looks alive until something
+//! is sent on it. Each side pings the other at an interval, and gives up on a peer it hasn't heard
+//! anything from, pongs included, for two intervals.
+
+use log::trace;
+use std::env;
+use std::time::{Duration, Instant};
+use ws::util::Token;
+
+const DEFAULT_INTERVAL: u64 = 30;
+
+/// Timeout token for heartbeats.
+pub const BEAT: Token = Token(1);
+
+pub struct Heartbeat {
+    /// Seconds between pings, zero to disable
+    interval: u64,
+
+    /// When anything was last received from the peer
+    last_seen: Instant,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        let interval = env::var("TREBUCHET_HEARTBEAT")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(DEFAULT_INTERVAL);
+
+        Self {
+            interval,
+            last_seen: Instant::now(),
+        }
+    }
+}
+
+impl Heartbeat {
+    /// Schedules the first beat.
+    pub fn start(&mut self, sender: &ws::Sender) -> ws::Result<()> {
+        self.last_seen = Instant::now();
+        if self.interval == 0 {
+            return Ok(());
+        }
+
+        sender.timeout(self.interval * 1000, BEAT)
+    }
+
+    /// Records that something was heard from the peer.
+    pub fn seen(&mut self) {
+        self.last_seen = Instant::now();
+    }
+
+    /// Pings the peer and schedules the next beat, or returns false if it's been silent too long.
+    pub fn beat(&mut self, sender: &ws::Sender) -> ws::Result<bool> {
+        let silence = self.last_seen.elapsed();
+        if silence > Duration::from_secs(self.interval * 2) {
+            return Ok(false);
+        }
+
+        trace!("heartbeat, peer last seen {:?} ago", silence);
+        sender.ping(Vec::new())?;
+        sender.timeout(self.interval * 1000, BEAT)?;
+        Ok(true)
+    }
+}
@@ -1,37 +1,199 @@
+use crate::{dispatch, rpc::app_error};
 use chashmap::CHashMap;
-use crossbeam_channel::{bounded, Receiver, Sender};
-use jsonrpc_core::{Id, Response};
-use log::trace;
+use jsonrpc_core::{
+    futures::{sync::oneshot, task, Async, Future, Poll},
+    Error, Id, Response,
+};
+use log::{debug, error, trace, warn};
+use serde_json::json;
+use std::env;
 use std::sync::{
     atomic::{AtomicUsize, Ordering},
     Arc,
 };
+use std::time::{Duration, Instant};
+
+/// How long calls wait for a response unless told otherwise, in seconds.
+const DEFAULT_TIMEOUT: u64 = 60;
 
-#[derive(Clone, Default)]
+#[derive(Clone)]
 pub struct Inflight {
     counter: Arc<AtomicUsize>,
-    store: Arc<CHashMap<Id, Sender<Response>>>,
+    store: Arc<CHashMap<Id, oneshot::Sender<Response>>>,
+    timeout: Duration,
+}
+
+impl Default for Inflight {
+    fn default() -> Self {
+        let timeout = env::var("TREBUCHET_CALL_TIMEOUT")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(DEFAULT_TIMEOUT);
+
+        Self {
+            counter: Arc::default(),
+            store: Arc::default(),
+            timeout: Duration::from_secs(timeout),
+        }
+    }
 }
 
 impl Inflight {
-    pub fn launch(&self) -> (Id, Receiver<Response>) {
+    /// The default deadline for calls.
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn launch(&self, method: &str) -> (Id, PendingCall) {
         trace!("incrementic atomic");
         let id = Id::Num(self.counter.fetch_add(1, Ordering::AcqRel) as u64);
-        let (tx, rx) = bounded(0);
+        let (tx, rx) = oneshot::channel();
 
         {
             trace!("insert {:?} into inflight log", id);
             self.store.insert(id.clone(), tx);
         }
 
-        (id, rx)
+        (
+            id.clone(),
+            PendingCall {
+                id,
+                method: method.into(),
+                rx,
+                inflight: self.clone(),
+                timeout: self.timeout,
+                deadline: Instant::now() + self.timeout,
+                timer_set: false,
+            },
+        )
     }
 
-    pub fn recall(&self, id: &Id) -> Option<Sender<Response>> {
+    pub fn recall(&self, id: &Id) -> Option<oneshot::Sender<Response>> {
         trace!("remove {:?} from inflight log", id);
         self.store.remove(id).map(|tx| {
             trace!("{:?} was in log", id);
             tx
         })
     }
+
+    /// Fails a call with an error, if it's still waiting.
+    pub fn fail(&self, id: &Id, error: Error) -> bool {
+        self.recall(id)
+            .map_or(false, |tx| tx.send(Response::from(error, None)).is_ok())
+    }
+
+    /// Fails all calls still waiting, e.g. when the connection goes away.
+    pub fn fail_all(&self, reason: &str) {
+        let pending = self.store.clear();
+        if !pending.is_empty() {
+            debug!("failing {} inflight calls: {}", pending.len(), reason);
+        }
+
+        for (_, tx) in pending {
+            tx.send(Response::from(app_error(64, reason, None), None))
+                .ok();
+        }
+    }
+}
+
+/// The response to a call, once it arrives.
+///
+/// Resolves to a timeout error if no response comes by the deadline.
+pub struct PendingCall {
+    id: Id,
+    method: String,
+    rx: oneshot::Receiver<Response>,
+    inflight: Inflight,
+    timeout: Duration,
+    deadline: Instant,
+    timer_set: bool,
+}
+
+impl PendingCall {
+    /// The id of the call, e.g. to cancel it.
+    pub fn id(&self) -> &Id {
+        &self.id
+    }
+
+    /// Overrides the default deadline, counting from now.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self.deadline = Instant::now() + timeout;
+        self.timer_set = false;
+        self
+    }
+}
+
+impl Drop for PendingCall {
+    fn drop(&mut self) {
+        // a call given up on before it's done shouldn't linger in the log
+        self.inflight.recall(&self.id);
+    }
+}
+
+impl Future for PendingCall {
+    type Item = Response;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Response, Error> {
+        match self.rx.poll() {
+            Ok(Async::Ready(res)) => {
+                trace!("got response from agent: {:?}", res);
+                Ok(Async::Ready(res))
+            }
+            Ok(Async::NotReady) => {
+                if Instant::now() >= self.deadline {
+                    self.inflight.recall(&self.id);
+                    warn!("call to {} ({:?}) timed out", self.method, self.id);
+                    return Err(app_error(
+                        408,
+                        "call timed out",
+                        Some(json!({
+                            "method": self.method,
+                            "timeout": self.timeout.as_secs(),
+                        })),
+                    ));
+                }
+
+                if !self.timer_set {
+                    dispatch::notify_at(self.deadline, task::current());
+                    self.timer_set = true;
+                }
+
+                Ok(Async::NotReady)
+            }
+            Err(err) => {
+                error!("response (rpc: {}) channel error: {:?}", self.method, err);
+                Err(app_error(64, "channel disconnected", None))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dropped_calls_leave_the_log() {
+        let inflight = Inflight::default();
+        let (id, call) = inflight.launch("test");
+        let (other, _pending) = inflight.launch("test");
+
+        drop(call);
+        assert!(inflight.recall(&id).is_none());
+        assert!(inflight.recall(&other).is_some());
+    }
+
+    #[test]
+    fn answered_calls_resolve_after_leaving_the_log() {
+        let inflight = Inflight::default();
+        let (id, call) = inflight.launch("test");
+
+        let tx = inflight.recall(&id).expect("call should be in the log");
+        tx.send(Response::from(app_error(1, "test", None), None))
+            .expect("call should be waiting");
+
+        assert!(call.wait().is_ok());
+    }
 }
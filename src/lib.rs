@@ -11,18 +11,24 @@
 extern crate diesel;
 
 use log::debug;
+use sha2::{Digest, Sha256};
 use std::env;
+use std::io::{Read, Result as IoResult};
 
+pub mod api;
 mod bus;
 pub mod castle;
 pub mod client;
 pub mod db;
+mod dispatch;
 mod error;
+mod heartbeat;
 mod inflight;
 mod message;
 pub mod rpc;
+pub mod tls;
 
-pub use bus::{central, Bus};
+pub use bus::{central, Bus, BusStats, Incoming, Limit, Overflow, RequestError};
 pub use error::Error as CommonError;
 
 lazy_static::lazy_static! {
@@ -82,3 +88,19 @@ pub fn init_with_level(verbosity: i8) {
         }
     }
 }
+
+/// Hex-encoded SHA-256 of everything in a reader.
+fn checksum<R: Read>(mut reader: R) -> IoResult<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+
+        hasher.input(&buf[..n]);
+    }
+
+    Ok(format!("{:x}", hasher.result()))
+}
@@ -5,7 +5,7 @@ use jsonrpc_core::{
 use log::{trace, warn};
 use serde_derive::{Deserialize, Serialize};
 use serde_json::json;
-use std::io::Write;
+use std::io::{self, Write};
 
 /// Either of a JSON-RPC Request or Response.
 #[derive(Debug, Deserialize, Serialize)]
@@ -56,15 +56,25 @@ pub fn parse_plain(string: &str) -> Option<RpcMessage> {
 /// The format is loosely inspired by Khronos' binary GLTF.
 ///
 /// The binary data is expected to contain:
-///  - **One byte** as version (expected to be `1`),
+///  - **One byte** as version (`1` or `2`),
 ///  - **Four bytes** as header length, little-endian u32,
 ///  - **Header length bytes** as header, UTF-8 string parsed as JSON-RPC,
-///  - **One byte** as the number of chunks, omitted if none,
-///  - **Remainder** as raw binary chunks, each organised such:
-///    - **Four bytes** as chunk length, little-endian u32,
-///    - **Chunk length bytes** as a chunk, raw binary data,
+///  - then, for **version 1**:
+///    - **One byte** as the number of chunks, omitted if none,
+///    - **Remainder** as raw binary chunks, each organised such:
+///      - **Four bytes** as chunk length, little-endian u32,
+///      - **Chunk length bytes** as a chunk, raw binary data,
+///  - or, for **version 2**:
+///    - **Varint** as the number of chunks,
+///    - raw binary chunks, each organised such:
+///      - **One byte** of flags: `1` if the chunk is named, `2` if it has a content type,
+///      - if named, **varint** as name length, then the name as UTF-8,
+///      - if typed, **varint** as content type length, then the content type as UTF-8,
+///      - **Varint** as chunk length,
+///      - **Chunk length bytes** as a chunk, raw binary data,
+///    - **Four bytes** as a CRC-32 of everything before it, little-endian u32,
 ///
-/// for a minimum length of 35 bytes.
+/// for a minimum length of 35 bytes. Varints are unsigned LEB128.
 ///
 /// The handling of the binary data depends on the type of the JSON-RPC message:
 ///  - If a **Notification**, treat the same as a Request.
@@ -75,7 +85,12 @@ pub fn parse_plain(string: &str) -> Option<RpcMessage> {
 ///     + if a **Structured**, proceed as for Request,
 ///     + if a **Primitive**, replace with an Array containing `[original, ...chunks]`.
 ///
-/// Only single JSON-RPC calls and responses are supported, not batches.
+/// Chunks are given as arrays of bytes, unless they have a name or content type, in which case
+/// they're given as a `Chunk` object.
+///
+/// For **batches** (version 2 only), each chunk is addressed to an element of the batch by its
+/// name, which starts with the element's index: `3` for a nameless chunk, or `3/name`. Each element
+/// is then handled as above with its own chunks.
 pub fn parse_binary(raw: &[u8]) -> Option<RpcMessage> {
     let len = raw.len();
     if len < 35 {
@@ -86,17 +101,34 @@ pub fn parse_binary(raw: &[u8]) -> Option<RpcMessage> {
         return None;
     }
 
-    let version = &raw[0];
-    if version != &1 {
-        warn!(
-            "invalid raw message version ({}) received, ignoring",
-            version
-        );
-        return None;
-    }
+    let version = raw[0];
+    let raw = match version {
+        1 => raw,
+        2 => {
+            let (body, trailer) = raw.split_at(len - 4);
+            let expected = LittleEndian::read_u32(trailer);
+            let actual = crc32fast::hash(body);
+            if expected != actual {
+                warn!(
+                    "invalid raw message checksum ({:08x}, expected {:08x}), ignoring",
+                    actual, expected
+                );
+                return None;
+            }
+
+            body
+        }
+        _ => {
+            warn!(
+                "invalid raw message version ({}) received, ignoring",
+                version
+            );
+            return None;
+        }
+    };
 
     // parse as header size + header + body
-    let size = LittleEndian::read_u32(&raw[1..4]) as usize;
+    let size = LittleEndian::read_u32(&raw[1..5]) as usize;
     if size + 5 > raw.len() {
         warn!(
             "invalid raw message header size ({}) received, ignoring",
@@ -105,7 +137,11 @@ pub fn parse_binary(raw: &[u8]) -> Option<RpcMessage> {
         return None;
     }
 
-    let chunks = parse_chunks(&raw[(6 + size)..]);
+    let chunks = if version == 1 {
+        parse_chunks(&raw[(5 + size)..])
+    } else {
+        parse_chunks_v2(&raw[(5 + size)..])?
+    };
 
     let header = match std::str::from_utf8(&raw[5..(5 + size)]) {
         Err(err) => {
@@ -121,46 +157,96 @@ pub fn parse_binary(raw: &[u8]) -> Option<RpcMessage> {
             None
         }
         Ok(RpcMessage::Request(Request::Single(mut req))) => {
-            match req {
-                Call::Invalid { .. } => {
-                    warn!("invalid raw message header: invalid call");
-                    return None;
-                }
-                Call::MethodCall(ref mut meth) => {
-                    meth.params = append_params(&meth.params, chunks);
-                }
-                Call::Notification(ref mut note) => {
-                    note.params = append_params(&note.params, chunks);
-                }
-            };
-
+            attach_call(&mut req, chunk_values(chunks))?;
             trace!("valid binary request parsed: {:?}", req);
             Some(RpcMessage::Request(Request::Single(req)))
         }
         Ok(RpcMessage::Response(Response::Single(mut res))) => {
-            match res {
-                Output::Success(ref mut succ) => {
-                    succ.result = append_values(&succ.result, chunks);
-                }
-                Output::Failure(ref mut fail) => {
-                    fail.error.data = Some(match fail.error.data {
-                        None => Value::Array(chunks),
-                        Some(ref val) => append_values(&val, chunks),
-                    });
-                }
-            };
-
+            attach_output(&mut res, chunk_values(chunks));
             trace!("valid binary response parsed: {:?}", res);
             Some(RpcMessage::Response(Response::Single(res)))
         }
-        _ => {
-            warn!("invalid raw message header: batch");
-            None
+        Ok(RpcMessage::Request(Request::Batch(mut reqs))) => {
+            let split = split_batch(reqs.len(), chunks)?;
+            for (req, chunks) in reqs.iter_mut().zip(split) {
+                attach_call(req, chunks)?;
+            }
+
+            trace!("valid binary batch request parsed: {:?}", reqs);
+            Some(RpcMessage::Request(Request::Batch(reqs)))
+        }
+        Ok(RpcMessage::Response(Response::Batch(mut outs))) => {
+            let split = split_batch(outs.len(), chunks)?;
+            for (out, chunks) in outs.iter_mut().zip(split) {
+                attach_output(out, chunks);
+            }
+
+            trace!("valid binary batch response parsed: {:?}", outs);
+            Some(RpcMessage::Response(Response::Batch(outs)))
         }
     }
 }
 
-fn parse_chunks(data: &[u8]) -> Vec<Value> {
+fn attach_call(req: &mut Call, chunks: Vec<Value>) -> Option<()> {
+    match req {
+        Call::Invalid { .. } => {
+            warn!("invalid raw message header: invalid call");
+            return None;
+        }
+        Call::MethodCall(ref mut meth) => {
+            meth.params = append_params(&meth.params, chunks);
+        }
+        Call::Notification(ref mut note) => {
+            note.params = append_params(&note.params, chunks);
+        }
+    };
+
+    Some(())
+}
+
+fn attach_output(res: &mut Output, chunks: Vec<Value>) {
+    match res {
+        Output::Success(ref mut succ) => {
+            succ.result = append_values(&succ.result, chunks);
+        }
+        Output::Failure(ref mut fail) => {
+            fail.error.data = Some(match fail.error.data {
+                None => Value::Array(chunks),
+                Some(ref val) => append_values(&val, chunks),
+            });
+        }
+    };
+}
+
+fn chunk_values(chunks: Vec<Chunk>) -> Vec<Value> {
+    chunks.into_iter().map(Chunk::into_value).collect()
+}
+
+/// Sorts chunks into the batch elements they're addressed to, stripping the address.
+fn split_batch(elements: usize, chunks: Vec<Chunk>) -> Option<Vec<Vec<Value>>> {
+    let mut split = vec![Vec::new(); elements];
+    for mut chunk in chunks {
+        let name = chunk.name.take().unwrap_or_default();
+        let mut parts = name.splitn(2, '/');
+        let index = parts.next().and_then(|i| i.parse::<usize>().ok());
+        chunk.name = parts.next().filter(|n| !n.is_empty()).map(String::from);
+
+        match index {
+            Some(i) if i < elements => split[i].push(chunk.into_value()),
+            _ => {
+                warn!(
+                    "invalid raw message: chunk {:?} is not addressed to a batch element",
+                    name
+                );
+                return None;
+            }
+        }
+    }
+
+    Some(split)
+}
+
+fn parse_chunks(data: &[u8]) -> Vec<Chunk> {
     if data.is_empty() {
         return Vec::new();
     }
@@ -174,15 +260,20 @@ fn parse_chunks(data: &[u8]) -> Vec<Value> {
     while n > 0 {
         n -= 1;
 
-        if cursor + 5 > overall {
+        if cursor + 4 > overall {
             warn!("invalid raw message: chunks are too short");
             return Vec::new();
         }
 
-        let len = LittleEndian::read_u32(&data[cursor..(cursor + 3)]) as usize;
+        let len = LittleEndian::read_u32(&data[cursor..(cursor + 4)]) as usize;
         cursor += 4;
 
-        chunks.push(&data[cursor..(cursor + len - 1)]);
+        if cursor + len > overall {
+            warn!("invalid raw message: chunk is too short");
+            return Vec::new();
+        }
+
+        chunks.push(&data[cursor..(cursor + len)]);
         cursor += len;
     }
 
@@ -195,6 +286,156 @@ fn parse_chunks(data: &[u8]) -> Vec<Value> {
         .collect()
 }
 
+/// A binary chunk along with its optional metadata.
+///
+/// Only version 2 messages can carry the metadata.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Chunk {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(
+        default,
+        rename = "contentType",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub content_type: Option<String>,
+    pub data: Vec<u8>,
+}
+
+impl Chunk {
+    /// The JSON form of the chunk: plain bytes if it has no metadata.
+    pub fn into_value(self) -> Value {
+        if self.name.is_none() && self.content_type.is_none() {
+            self.data.into()
+        } else {
+            json!(self)
+        }
+    }
+}
+
+impl From<Vec<u8>> for Chunk {
+    fn from(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            ..Self::default()
+        }
+    }
+}
+
+/// Either JSON form of a chunk.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ChunkValue {
+    Plain(Vec<u8>),
+    Described(Chunk),
+}
+
+impl From<ChunkValue> for Chunk {
+    fn from(value: ChunkValue) -> Self {
+        match value {
+            ChunkValue::Plain(data) => data.into(),
+            ChunkValue::Described(chunk) => chunk,
+        }
+    }
+}
+
+const CHUNK_NAMED: u8 = 1;
+const CHUNK_TYPED: u8 = 2;
+
+fn parse_chunks_v2(data: &[u8]) -> Option<Vec<Chunk>> {
+    let mut cursor = 0;
+    let n = read_varint(data, &mut cursor)?;
+
+    // each chunk takes at least two bytes, don't trust the count further than that
+    let mut chunks = Vec::with_capacity(n.min(data.len() / 2));
+    for _ in 0..n {
+        let flags = *data.get(cursor)?;
+        cursor += 1;
+
+        let name = if flags & CHUNK_NAMED == 0 {
+            None
+        } else {
+            Some(read_string(data, &mut cursor)?)
+        };
+
+        let content_type = if flags & CHUNK_TYPED == 0 {
+            None
+        } else {
+            Some(read_string(data, &mut cursor)?)
+        };
+
+        let bytes = read_bytes(data, &mut cursor)?;
+        chunks.push(Chunk {
+            name,
+            content_type,
+            data: bytes.into(),
+        });
+    }
+
+    if cursor != data.len() {
+        warn!(
+            "invalid raw message: {} trailing bytes after chunks",
+            data.len() - cursor
+        );
+        return None;
+    }
+
+    Some(chunks)
+}
+
+fn read_varint(data: &[u8], cursor: &mut usize) -> Option<usize> {
+    let mut value: usize = 0;
+    let mut shift = 0;
+    loop {
+        let byte = match data.get(*cursor) {
+            Some(byte) => *byte,
+            None => {
+                warn!("invalid raw message: varint is too short");
+                return None;
+            }
+        };
+        *cursor += 1;
+
+        if shift >= std::mem::size_of::<usize>() * 8 {
+            warn!("invalid raw message: varint is too large");
+            return None;
+        }
+
+        value |= usize::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+
+        shift += 7;
+    }
+}
+
+fn read_bytes<'a>(data: &'a [u8], cursor: &mut usize) -> Option<&'a [u8]> {
+    let len = read_varint(data, cursor)?;
+    let end = cursor.checked_add(len)?;
+    if end > data.len() {
+        warn!("invalid raw message: chunk is too short");
+        return None;
+    }
+
+    let bytes = &data[*cursor..end];
+    *cursor = end;
+    Some(bytes)
+}
+
+fn read_string(data: &[u8], cursor: &mut usize) -> Option<String> {
+    match std::str::from_utf8(read_bytes(data, cursor)?) {
+        Ok(s) => Some(s.into()),
+        Err(err) => {
+            warn!(
+                "invalid raw message chunk metadata (utf-8 parsing error): {}",
+                err
+            );
+            None
+        }
+    }
+}
+
 fn append_params(params: &Params, chunks: Vec<Value>) -> Params {
     match params {
         Params::None => {
@@ -236,6 +477,63 @@ fn append_values(value: &Value, mut chunks: Vec<Value>) -> Value {
     }
 }
 
+/// Serialises a response, moving any `.raw` result data out into binary chunks.
+///
+/// This is the reverse of what `parse_binary` does for a **Structured** response, so handlers can
+/// return binary data by putting an array of byte arrays (or `Chunk` objects, to name them or give
+/// them a content type) under the `.raw` key of an object result.
+pub fn response(mut res: Response) -> ws::Message {
+    let chunks = match res {
+        Response::Single(Output::Success(ref mut succ)) => take_raw(&mut succ.result),
+        Response::Single(_) => Vec::new(),
+        Response::Batch(ref mut outs) => outs
+            .iter_mut()
+            .enumerate()
+            .flat_map(|(i, out)| match out {
+                Output::Success(ref mut succ) => take_raw(&mut succ.result)
+                    .into_iter()
+                    .map(|chunk| address(i, chunk))
+                    .collect(),
+                Output::Failure(_) => Vec::new(),
+            })
+            .collect(),
+    };
+
+    let header = json!(res).to_string();
+    if chunks.is_empty() {
+        header.into()
+    } else {
+        add_described_chunks(header, &chunks).into()
+    }
+}
+
+fn take_raw(value: &mut Value) -> Vec<Chunk> {
+    let map = match value.as_object_mut() {
+        Some(map) => map,
+        None => return Vec::new(),
+    };
+
+    let chunks: Vec<ChunkValue> = match map
+        .get(".raw")
+        .map(|raw| serde_json::from_value(raw.clone()))
+    {
+        Some(Ok(chunks)) => chunks,
+        _ => return Vec::new(),
+    };
+
+    map.remove(".raw");
+    chunks.into_iter().map(Chunk::from).collect()
+}
+
+/// Addresses a chunk to an element of a batch, keeping its own name after the index.
+pub fn address(index: usize, mut chunk: Chunk) -> Chunk {
+    chunk.name = Some(match chunk.name {
+        Some(name) => format!("{}/{}", index, name),
+        None => index.to_string(),
+    });
+    chunk
+}
+
 pub fn notification(method: String, params: Params) -> String {
     json!(Request::Single(
         Notification {
@@ -261,18 +559,412 @@ pub fn methodcall(method: String, params: Params, id: Id) -> String {
     .to_string()
 }
 
-#[allow(clippy::cast_possible_truncation)]
+pub fn methodcall_batch(calls: Vec<(String, Params, Id)>) -> String {
+    json!(Request::Batch(
+        calls
+            .into_iter()
+            .map(|(method, params, id)| {
+                MethodCall {
+                    jsonrpc: Some(Version::V2),
+                    method,
+                    params,
+                    id,
+                }
+                .into()
+            })
+            .collect(),
+    ))
+    .to_string()
+}
+
+/// Builds a binary message (version 2) from a header and plain chunks.
 pub fn add_chunks(header: String, chunks: &[&[u8]]) -> Vec<u8> {
-    let headlen = header.len();
-    let rawlen = chunks.iter().fold(1, |sum, c| sum + c.len());
-    let mut buf = Vec::with_capacity(5 + headlen + rawlen);
-    buf.write_all(&[1]).unwrap(); // version
-    buf.write_u32::<LittleEndian>(headlen as u32).unwrap();
-    buf.write_all(header.as_bytes()).unwrap();
-    buf.write_u8(chunks.len() as u8).unwrap();
+    let rawlen = chunks.iter().fold(0, |sum, c| sum + 11 + c.len());
+    let buf = Vec::with_capacity(header.len() + rawlen + 19);
+    let mut frame =
+        FrameWriter::new(buf, &header, chunks.len()).expect("writing to a vec cannot fail");
     for chunk in chunks {
-        buf.write_u32::<LittleEndian>(chunk.len() as u32).unwrap();
-        buf.write_all(chunk).unwrap();
+        frame
+            .chunk(None, None, chunk)
+            .expect("writing to a vec cannot fail");
+    }
+    frame.finish().expect("writing to a vec cannot fail")
+}
+
+/// Builds a binary message (version 2) from a header and chunks with metadata.
+pub fn add_described_chunks(header: String, chunks: &[Chunk]) -> Vec<u8> {
+    let rawlen = chunks.iter().fold(0, |sum, c| sum + 11 + c.data.len());
+    let buf = Vec::with_capacity(header.len() + rawlen + 19);
+    let mut frame =
+        FrameWriter::new(buf, &header, chunks.len()).expect("writing to a vec cannot fail");
+    for chunk in chunks {
+        frame
+            .chunk(
+                chunk.name.as_ref().map(String::as_str),
+                chunk.content_type.as_ref().map(String::as_str),
+                &chunk.data,
+            )
+            .expect("writing to a vec cannot fail");
+    }
+    frame.finish().expect("writing to a vec cannot fail")
+}
+
+/// Writes a version 2 binary message piece by piece, into any writer.
+///
+/// WebSocket messages are sent whole, so this mostly writes into a `Vec` (see `add_chunks`), but
+/// it does save copying chunks around before framing them. The checksum trailer is written by
+/// `finish()`.
+pub struct FrameWriter<W: Write> {
+    writer: W,
+    hasher: crc32fast::Hasher,
+    remaining: usize,
+}
+
+impl<W: Write> FrameWriter<W> {
+    /// Starts a message with its header, announcing how many chunks will follow.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn new(writer: W, header: &str, chunks: usize) -> io::Result<Self> {
+        if header.len() > u32::max_value() as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "message header is too large",
+            ));
+        }
+
+        let mut frame = Self {
+            writer,
+            hasher: crc32fast::Hasher::new(),
+            remaining: chunks,
+        };
+
+        let mut start = Vec::with_capacity(5 + header.len() + 10);
+        start.write_u8(2)?; // version
+        start.write_u32::<LittleEndian>(header.len() as u32)?;
+        start.write_all(header.as_bytes())?;
+        write_varint(&mut start, chunks)?;
+        frame.put(&start)?;
+
+        Ok(frame)
+    }
+
+    /// Writes the next chunk.
+    pub fn chunk(
+        &mut self,
+        name: Option<&str>,
+        content_type: Option<&str>,
+        data: &[u8],
+    ) -> io::Result<()> {
+        if self.remaining == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "more chunks than announced",
+            ));
+        }
+        self.remaining -= 1;
+
+        let mut flags = 0;
+        if name.is_some() {
+            flags |= CHUNK_NAMED;
+        }
+        if content_type.is_some() {
+            flags |= CHUNK_TYPED;
+        }
+
+        let mut meta = Vec::with_capacity(32);
+        meta.write_u8(flags)?;
+        for s in name.iter().chain(content_type.iter()) {
+            write_varint(&mut meta, s.len())?;
+            meta.write_all(s.as_bytes())?;
+        }
+        write_varint(&mut meta, data.len())?;
+
+        self.put(&meta)?;
+        self.put(data)
+    }
+
+    /// Writes the checksum trailer and returns the underlying writer.
+    pub fn finish(self) -> io::Result<W> {
+        if self.remaining > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} announced chunks missing", self.remaining),
+            ));
+        }
+
+        let Self {
+            mut writer, hasher, ..
+        } = self;
+        writer.write_u32::<LittleEndian>(hasher.finalize())?;
+        Ok(writer)
+    }
+
+    fn put(&mut self, data: &[u8]) -> io::Result<()> {
+        self.hasher.update(data);
+        self.writer.write_all(data)
+    }
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn write_varint<W: Write>(writer: &mut W, mut value: usize) -> io::Result<()> {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            return writer.write_u8(byte);
+        }
+
+        writer.write_u8(byte | 0x80)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call() -> String {
+        methodcall("test".into(), Params::Array(vec![json!(1)]), Id::Num(1))
+    }
+
+    fn parsed(raw: &[u8]) -> Value {
+        json!(parse_binary(raw).expect("message should parse"))
+    }
+
+    fn called_with(params: Value) -> Value {
+        json!({ "jsonrpc": "2.0", "method": "test", "params": params, "id": 1 })
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn v1(header: &str, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut raw = vec![1];
+        raw.write_u32::<LittleEndian>(header.len() as u32).unwrap();
+        raw.extend(header.as_bytes());
+        if !chunks.is_empty() {
+            raw.push(chunks.len() as u8);
+            for chunk in chunks {
+                raw.write_u32::<LittleEndian>(chunk.len() as u32).unwrap();
+                raw.extend(*chunk);
+            }
+        }
+        raw
+    }
+
+    /// Frames hand-written chunk data as a version 2 message, with a valid checksum.
+    #[allow(clippy::cast_possible_truncation)]
+    fn v2(header: &str, tail: &[u8]) -> Vec<u8> {
+        let mut raw = vec![2];
+        raw.write_u32::<LittleEndian>(header.len() as u32).unwrap();
+        raw.extend(header.as_bytes());
+        raw.extend(tail);
+        let crc = crc32fast::hash(&raw);
+        raw.write_u32::<LittleEndian>(crc).unwrap();
+        raw
+    }
+
+    #[test]
+    fn v1_still_parses() {
+        assert_eq!(parsed(&v1(&call(), &[])), called_with(json!([1])));
+        assert_eq!(
+            parsed(&v1(&call(), &[&[1, 2, 3], &[]])),
+            called_with(json!([1, [1, 2, 3], []]))
+        );
+    }
+
+    #[test]
+    fn v1_short_chunk_drops_chunks() {
+        let mut raw = v1(&call(), &[&[1, 2, 3]]);
+        raw.pop();
+        assert_eq!(parsed(&raw), called_with(json!([1])));
+    }
+
+    #[test]
+    fn v2_matches_the_format() {
+        let tail = [2, 0, 2, 4, 5, 0, 0];
+        assert_eq!(add_chunks(call(), &[&[4, 5], &[]]), v2(&call(), &tail));
+        assert_eq!(
+            parsed(&v2(&call(), &tail)),
+            called_with(json!([1, [4, 5], []]))
+        );
+    }
+
+    #[test]
+    #[allow(clippy::cast_possible_truncation)]
+    fn v2_with_many_chunks() {
+        let data: Vec<Vec<u8>> = (0..300).map(|i| vec![i as u8; i % 3]).collect();
+        let chunks: Vec<&[u8]> = data.iter().map(Vec::as_slice).collect();
+
+        let mut expected = vec![json!(1)];
+        expected.extend(data.iter().map(|chunk| json!(chunk)));
+        assert_eq!(
+            parsed(&add_chunks(call(), &chunks)),
+            called_with(Value::Array(expected))
+        );
+    }
+
+    #[test]
+    fn named_and_typed_chunks() {
+        let chunks = vec![
+            Chunk::from(vec![1]),
+            Chunk {
+                name: Some("named".into()),
+                ..vec![2].into()
+            },
+            Chunk {
+                content_type: Some("text/plain".into()),
+                ..vec![3].into()
+            },
+            Chunk {
+                name: Some("both".into()),
+                content_type: Some("application/x-tar".into()),
+                data: vec![4, 5],
+            },
+        ];
+
+        assert_eq!(
+            parsed(&add_described_chunks(call(), &chunks)),
+            called_with(json!([
+                1,
+                [1],
+                { "name": "named", "data": [2] },
+                { "contentType": "text/plain", "data": [3] },
+                { "name": "both", "contentType": "application/x-tar", "data": [4, 5] },
+            ]))
+        );
+    }
+
+    #[test]
+    fn object_params_get_raw_key() {
+        let header = methodcall(
+            "test".into(),
+            Params::Map(serde_json::Map::new()),
+            Id::Num(1),
+        );
+        assert_eq!(
+            parsed(&add_chunks(header, &[&[1]])),
+            called_with(json!({ ".raw": [[1]] }))
+        );
+    }
+
+    #[test]
+    fn response_round_trips_raw_result() {
+        let result = json!({ "ok": true, ".raw": [[1, 2], { "name": "log", "data": [3] }] });
+        let res = Response::Single(Output::Success(jsonrpc_core::Success {
+            jsonrpc: Some(Version::V2),
+            result: result.clone(),
+            id: Id::Num(7),
+        }));
+
+        let raw = match response(res) {
+            ws::Message::Binary(raw) => raw,
+            msg => panic!("expected a binary message, got {:?}", msg),
+        };
+
+        assert_eq!(
+            parsed(&raw),
+            json!({ "jsonrpc": "2.0", "result": result, "id": 7 })
+        );
+    }
+
+    #[test]
+    fn checksum_mismatch_is_rejected() {
+        let raw = add_chunks(call(), &[&[1, 2, 3]]);
+        assert!(parse_binary(&raw).is_some());
+
+        for i in &[0, 10, raw.len() - 6, raw.len() - 1] {
+            let mut bad = raw.clone();
+            bad[*i] ^= 0x10;
+            assert!(parse_binary(&bad).is_none(), "flipped byte {}", i);
+        }
+    }
+
+    #[test]
+    fn truncated_message_is_rejected() {
+        let raw = add_chunks(call(), &[&[1, 2, 3]]);
+        assert!(parse_binary(&raw[..raw.len() - 1]).is_none());
+        assert!(parse_binary(&raw[..raw.len() - 5]).is_none());
+    }
+
+    #[test]
+    fn truncated_chunks_are_rejected() {
+        // chunk count cut short
+        assert!(parse_binary(&v2(&call(), &[0x80])).is_none());
+        // chunk length cut short
+        assert!(parse_binary(&v2(&call(), &[1, 0, 0x80])).is_none());
+        // chunk shorter than its length
+        assert!(parse_binary(&v2(&call(), &[1, 0, 10, 1, 2, 3])).is_none());
+        // name shorter than its length
+        assert!(parse_binary(&v2(&call(), &[1, 1, 5, b'a', 0])).is_none());
+        // fewer chunks than announced
+        assert!(parse_binary(&v2(&call(), &[2, 0, 1, 1])).is_none());
+        // more data than announced
+        assert!(parse_binary(&v2(&call(), &[1, 0, 1, 1, 0])).is_none());
+        // no chunk count at all
+        assert!(parse_binary(&v2(&call(), &[])).is_none());
+    }
+
+    #[test]
+    fn varints() {
+        for value in &[0, 1, 127, 128, 255, 300, 16_384, usize::max_value()] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, *value).unwrap();
+            let mut cursor = 0;
+            assert_eq!(read_varint(&buf, &mut cursor), Some(*value));
+            assert_eq!(cursor, buf.len());
+        }
+
+        let mut cursor = 0;
+        assert_eq!(read_varint(&[0xff; 11], &mut cursor), None);
+    }
+
+    #[test]
+    fn batch_chunks_are_addressed() {
+        let header = methodcall_batch(vec![
+            ("test".into(), Params::Array(vec![json!(1)]), Id::Num(1)),
+            ("test".into(), Params::Array(vec![json!(2)]), Id::Num(2)),
+            ("test".into(), Params::Array(vec![json!(3)]), Id::Num(3)),
+        ]);
+        let chunks = vec![
+            address(1, Chunk::from(vec![1])),
+            address(0, Chunk::from(vec![2])),
+            address(
+                1,
+                Chunk {
+                    name: Some("file".into()),
+                    ..vec![3].into()
+                },
+            ),
+        ];
+
+        let call =
+            |id, params| json!({ "jsonrpc": "2.0", "method": "test", "params": params, "id": id });
+        assert_eq!(
+            parsed(&add_described_chunks(header.clone(), &chunks)),
+            json!([
+                element(1, json!([1, [2]])),
+                element(2, json!([2, [1], { "name": "file", "data": [3] }])),
+                element(3, json!([3])),
+            ])
+        );
+
+        for name in &["3", "x", "x/file", ""] {
+            let stray = Chunk {
+                name: Some((*name).into()),
+                ..vec![4].into()
+            };
+            assert!(
+                parse_binary(&add_described_chunks(header.clone(), &[stray])).is_none(),
+                "chunk named {:?}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn frame_writer_counts_chunks() {
+        let mut frame = FrameWriter::new(Vec::new(), &call(), 1).unwrap();
+        frame.chunk(None, None, &[1]).unwrap();
+        assert!(frame.chunk(None, None, &[2]).is_err());
+
+        let frame = FrameWriter::new(Vec::new(), &call(), 2).unwrap();
+        assert!(frame.finish().is_err());
     }
-    buf
 }
@@ -1,14 +1,35 @@
 // Why JSON RPC? Simple, lightweight, well-established, can be hand-written in a pinch
 // Why Websocket? Duplex, inspectable, trivial to secure, can be used from browsers as-is
 
-use crate::{inflight::Inflight, message, CommonError};
+use crate::{
+    dispatch,
+    inflight::{Inflight, PendingCall},
+    message, CommonError,
+};
+use crossbeam_channel::{unbounded, Sender};
 use jsonrpc_core::{
-    futures::Future, Error, ErrorCode, IoHandler, Metadata, Output, Params, Response, Value,
+    futures::{
+        executor::{self, Notify},
+        future,
+        sync::oneshot,
+        Async, Future,
+    },
+    BoxFuture, Call, Error, ErrorCode, Id, IoHandler, Metadata, Output, Params, Request, Response,
+    Value,
 };
 use jsonrpc_macros::IoDelegate;
 use log::{debug, error, trace};
 use serde_json::json;
 use std::result::Result as StdResult;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Notification sent to a peer when a call it's handling is cancelled.
+///
+/// This is advisory only: handlers can't be interrupted, so the peer only logs it, and still sends
+/// its response once done. That response is then dropped, as the call has left the inflight log.
+const CANCEL_METHOD: &str = "rpc:cancel";
 
 pub fn app_error(code: i64, message: &str, data: Option<Value>) -> Error {
     Error {
@@ -26,6 +47,65 @@ pub fn param_map(params: Value) -> Params {
     Params::Map(params.as_object().unwrap().to_owned())
 }
 
+type Job = Box<dyn FnMut() + Send>;
+
+/// Runs handlers one at a time on a thread of their own.
+///
+/// Handlers otherwise run on the websocket thread, where anything slow holds up every connection.
+/// The thread ends once every copy of this is dropped, along with the handlers holding them.
+#[derive(Clone)]
+pub struct Offload {
+    jobs: Sender<Job>,
+}
+
+impl Offload {
+    pub fn new(name: &str) -> Self {
+        let (jobs, rx) = unbounded::<Job>();
+        thread::Builder::new()
+            .name(name.into())
+            .spawn(move || {
+                for mut job in rx.iter() {
+                    job();
+                }
+            })
+            .expect("failed to start handler thread");
+
+        Self { jobs }
+    }
+
+    /// Queues a handler, and returns a future of its result.
+    pub fn run<F>(&self, handler: F) -> BoxFuture<Value>
+    where
+        F: FnOnce() -> Result<Value, Error> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let mut job = Some((handler, tx));
+        let sent = self.jobs.send(Box::new(move || {
+            if let Some((handler, tx)) = job.take() {
+                tx.send(handler()).ok();
+            }
+        }));
+
+        if sent.is_err() {
+            return Box::new(future::err(gone()));
+        }
+
+        Box::new(rx.then(|res| res.unwrap_or_else(|_| Err(gone()))))
+    }
+}
+
+fn gone() -> Error {
+    app_error(500, "handler thread is gone", None)
+}
+
+/// Handlers that aren't done when first polled are handed to the dispatcher, which polls them
+/// again as its own task, so there's nothing to wake here.
+struct Handoff;
+
+impl Notify for Handoff {
+    fn notify(&self, _id: usize) {}
+}
+
 pub trait RpcDelegate {
     fn to_delegate<M: Metadata>(self) -> IoDelegate<Self, M>
     where
@@ -79,10 +159,7 @@ pub trait RpcClient {
             };
 
             for out in outs.into_iter() {
-                if let Err(err) = cb(match out {
-                    Output::Success(s) => Ok(s.result),
-                    Output::Failure(s) => Err(s.error),
-                }) {
+                if let Err(err) = cb(output_result(out)) {
                     error!("{:?}", err);
                 }
             }
@@ -99,43 +176,169 @@ pub trait RpcClient {
     where
         F: FnMut(Response) + Send + 'static,
     {
+        let call = self.call_async_response(method, params, binary)?;
+        dispatch::spawn(call.then(move |res| {
+            cb(res.unwrap_or_else(|err| Response::from(err, None)));
+            Ok(())
+        }));
+
+        Ok(())
+    }
+
+    /// Calls a method and returns a future of its result.
+    ///
+    /// Nothing needs to wait on the future for the call to be made, it's sent right away. The
+    /// future fails if there's no response within the default timeout.
+    fn call_async(
+        &self,
+        method: &str,
+        params: Params,
+        binary: &[&[u8]],
+    ) -> ws::Result<Box<dyn Future<Item = Value, Error = Error> + Send>> {
+        self.call_async_timeout(method, params, binary, self.inflight().timeout())
+    }
+
+    /// Calls a method and returns a future of its result, with a custom timeout.
+    fn call_async_timeout(
+        &self,
+        method: &str,
+        params: Params,
+        binary: &[&[u8]],
+        timeout: Duration,
+    ) -> ws::Result<Box<dyn Future<Item = Value, Error = Error> + Send>> {
+        Ok(Box::new(
+            self.call_async_response(method, params, binary)?
+                .timeout(timeout)
+                .and_then(|res| match res {
+                    Response::Single(out) => output_result(out),
+                    Response::Batch(mut many) => {
+                        debug!("got batch response to single request");
+                        many.pop().map_or_else(
+                            || Err(app_error(65, "empty batch response", None)),
+                            output_result,
+                        )
+                    }
+                }),
+        ))
+    }
+
+    /// Calls a method and returns a future of the raw response.
+    fn call_async_response(
+        &self,
+        method: &str,
+        params: Params,
+        binary: &[&[u8]],
+    ) -> ws::Result<PendingCall> {
         debug!("calling method {} with params: {:?}", method, params);
 
-        let (id, rx) = self.inflight().launch();
+        let (id, pending) = self.inflight().launch(method);
         trace!("requested new inflight id: {:?}", id);
 
         let msg: ws::Message = if binary.is_empty() {
-            message::methodcall(method.into(), params, id).into()
+            message::methodcall(method.into(), params, id.clone()).into()
         } else {
-            message::add_chunks(message::methodcall(method.into(), params, id), binary).into()
+            message::add_chunks(
+                message::methodcall(method.into(), params, id.clone()),
+                binary,
+            )
+            .into()
         };
 
         trace!("built method call (and about to send): {:?}", msg);
-        self.sender().send(msg)?;
+        if let Err(err) = self.sender().send(msg) {
+            self.inflight().recall(&id);
+            return Err(err);
+        }
 
-        trace!("spawn thread for response");
-        let method = method.to_owned();
-        std::thread::Builder::new()
-            .name(format!("response for {}", method))
-            .spawn(move || {
-                debug!("response (rpc: {}) thread start", method);
-                cb(match rx.recv() {
-                    Err(err) => {
-                        error!("response (rpc: {}) channel error: {:?}", method, err);
-                        Response::from(app_error(64, "channel disconnected", None), None)
-                    }
-                    Ok(res) => {
-                        trace!("got response from agent: {:?}", res);
-                        res
+        Ok(pending)
+    }
+
+    /// Calls several methods in a single batch message.
+    ///
+    /// Each call is tracked on its own, so results are handed to the callback independently and
+    /// in whichever order they arrive, along with the index of the call in the batch. Binary data
+    /// is addressed to its call in the batch.
+    fn call_batch<F>(&self, calls: Vec<(&str, Params, &[&[u8]])>, cb: F) -> ws::Result<()>
+    where
+        F: FnMut(usize, Result<Value, Error>) -> StdResult<(), CommonError> + Send + 'static,
+    {
+        debug!("calling batch of {} methods", calls.len());
+
+        let mut pending = Vec::with_capacity(calls.len());
+        let mut methods = Vec::with_capacity(calls.len());
+        let mut chunks = Vec::new();
+        for (i, (method, params, binary)) in calls.into_iter().enumerate() {
+            let (id, call) = self.inflight().launch(method);
+            trace!(
+                "requested new inflight id for batch element {}: {:?}",
+                i,
+                id
+            );
+
+            chunks.extend(
+                binary
+                    .iter()
+                    .map(|data| message::address(i, data.to_vec().into())),
+            );
+            pending.push((i, id.clone(), call));
+            methods.push((method.to_owned(), params, id));
+        }
+
+        let header = message::methodcall_batch(methods);
+        let msg: ws::Message = if chunks.is_empty() {
+            header.into()
+        } else {
+            message::add_described_chunks(header, &chunks).into()
+        };
+
+        trace!("built batch call (and about to send): {:?}", msg);
+        if let Err(err) = self.sender().send(msg) {
+            for (_, id, _) in pending {
+                self.inflight().recall(&id);
+            }
+
+            return Err(err);
+        }
+
+        let cb = Arc::new(Mutex::new(cb));
+        for (i, _, call) in pending {
+            let cb = cb.clone();
+            dispatch::spawn(call.then(move |res| {
+                let result = match res {
+                    Ok(Response::Single(out)) => output_result(out),
+                    Ok(Response::Batch(_)) => {
+                        error!("got batch response to batch element {}", i);
+                        return Ok(());
                     }
-                });
-                debug!("response (rpc: {}) thread end", method);
-            })
-            .expect("failed to start response thread");
+                    Err(err) => Err(err),
+                };
+
+                let mut cb = cb.lock().expect("batch callback poisoned");
+                if let Err(err) = (*cb)(i, result) {
+                    error!("{:?}", err);
+                }
+
+                Ok(())
+            }));
+        }
 
         Ok(())
     }
 
+    /// Gives up on a call, and lets the peer know (see `CANCEL_METHOD`: it carries on regardless).
+    fn cancel(&self, id: &Id) -> ws::Result<()> {
+        if self
+            .inflight()
+            .fail(id, app_error(499, "call cancelled", None))
+        {
+            debug!("cancelled call {:?}", id);
+            self.notify(CANCEL_METHOD, param_list(vec![json!(id)]))
+        } else {
+            trace!("call {:?} was already done, nothing to cancel", id);
+            Ok(())
+        }
+    }
+
     fn notify(&self, method: &str, params: Params) -> ws::Result<()> {
         self.notify_binary(method, params, &[])
     }
@@ -165,6 +368,23 @@ pub trait RpcClient {
     }
 }
 
+fn respond(sender: &ws::Sender, res: Option<Response>) -> ws::Result<()> {
+    if let Some(res) = res {
+        trace!("got rpc response back from handler: {:?}", res);
+        sender.send(message::response(res))
+    } else {
+        trace!("no rpc response back from handler (is it a notification?)");
+        Ok(())
+    }
+}
+
+fn output_result(out: Output) -> Result<Value, Error> {
+    match out {
+        Output::Success(s) => Ok(s.result),
+        Output::Failure(s) => Err(s.error),
+    }
+}
+
 pub trait RpcHandler: RpcClient {
     const PROTOCOL: &'static str;
 
@@ -201,14 +421,29 @@ pub trait RpcHandler: RpcClient {
     fn rpc_on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
         if let Some(rpc) = message::parse_ws(msg) {
             match rpc {
+                message::RpcMessage::Request(Request::Single(Call::Notification(ref note)))
+                    if note.method == CANCEL_METHOD =>
+                {
+                    // handlers can't be interrupted, the response goes out whenever they're done
+                    debug!("peer cancelled a call: {:?}", note.params);
+                }
                 message::RpcMessage::Request(req) => {
                     trace!("handing off rpc request for handling: {:?}", req);
 
-                    if let Some(res) = self.rpc().handle_rpc_request(req).wait().unwrap() {
-                        trace!("got rpc response back from handler: {:?}", res);
-                        self.sender().send(json!(res).to_string())?
-                    } else {
-                        trace!("no rpc response back from handler (is it a notification?)");
+                    // most handlers are done right away, the others (see `Offload`) are waited
+                    // on by the dispatcher rather than here on the websocket thread
+                    let mut handling = executor::spawn(self.rpc().handle_rpc_request(req));
+                    match handling.poll_future_notify(&Arc::new(Handoff), 0) {
+                        Ok(Async::Ready(res)) => respond(&self.sender(), res)?,
+                        Ok(Async::NotReady) => {
+                            let sender = self.sender();
+                            dispatch::spawn(handling.into_inner().map(move |res| {
+                                if let Err(err) = respond(&sender, res) {
+                                    error!("failed to send rpc response: {:?}", err);
+                                }
+                            }));
+                        }
+                        Err(()) => unreachable!(),
                     }
                 }
                 message::RpcMessage::Response(Response::Single(out)) => {
@@ -227,8 +462,19 @@ pub trait RpcHandler: RpcClient {
         Ok(())
     }
 
+    fn rpc_on_close(&mut self, code: ws::CloseCode, reason: &str) {
+        debug!(
+            "{} connection closing ({:?}): {}",
+            Self::PROTOCOL,
+            code,
+            reason
+        );
+        self.inflight().fail_all("connection closed");
+    }
+
     fn rpc_on_shutdown(&mut self) {
         debug!("{} connection closed", Self::PROTOCOL);
+        self.inflight().fail_all("connection closed");
     }
 
     fn handle_response(&self, out: Output) -> ws::Result<()> {
@@ -243,7 +489,9 @@ pub trait RpcHandler: RpcClient {
 
         if let Some(tx) = self.inflight().recall(id) {
             trace!("matched with existing id, sending response through");
-            tx.send(Response::Single(out)).expect("Internal comm error");
+            if tx.send(Response::Single(out)).is_err() {
+                debug!("response arrived after its call was given up on");
+            }
         }
 
         Ok(())
@@ -0,0 +1,383 @@
+//! TLS setup for the castle listener and client connections.
+//!
+//! Certificates and keys are read from local PEM files. When the castle is given a CA, it requires
+//! clients to present a certificate signed by it (mutual TLS), and the common name of that
+//! certificate identifies the client.
+
+use log::{debug, warn};
+use mio::tcp::TcpStream;
+use openssl::error::ErrorStack;
+use openssl::nid::Nid;
+use openssl::ssl::{
+    Ssl, SslAcceptor, SslConnector, SslFiletype, SslMethod, SslStream, SslVerifyMode,
+};
+use openssl::x509::X509Ref;
+use std::path::Path;
+
+/// Server side: our certificate and key, and the CA to verify clients against, if any.
+pub fn acceptor(cert: &Path, key: &Path, ca: Option<&Path>) -> Result<SslAcceptor, ErrorStack> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder.set_certificate_chain_file(cert)?;
+    builder.set_private_key_file(key, SslFiletype::PEM)?;
+    builder.check_private_key()?;
+
+    if let Some(ca) = ca {
+        builder.set_ca_file(ca)?;
+        builder.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+    }
+
+    Ok(builder.build())
+}
+
+/// Client side: the CA to verify the castle against (system roots otherwise), and our own
+/// certificate and key, for mutual TLS.
+pub fn connector(
+    ca: Option<&Path>,
+    identity: Option<(&Path, &Path)>,
+) -> Result<SslConnector, ErrorStack> {
+    let mut builder = SslConnector::builder(SslMethod::tls())?;
+
+    if let Some(ca) = ca {
+        builder.set_ca_file(ca)?;
+    }
+
+    if let Some((cert, key)) = identity {
+        builder.set_certificate_chain_file(cert)?;
+        builder.set_private_key_file(key, SslFiletype::PEM)?;
+        builder.check_private_key()?;
+    }
+
+    Ok(builder.build())
+}
+
+/// Starts the TLS handshake with a client.
+///
+/// Once the client certificate (if any) is verified, its common name is handed to `on_peer`.
+pub fn accept<F>(
+    acceptor: &SslAcceptor,
+    sock: TcpStream,
+    on_peer: F,
+) -> ws::Result<SslStream<TcpStream>>
+where
+    F: Fn(String) + Send + Sync + 'static,
+{
+    let context = acceptor.context();
+    let mut ssl = Ssl::new(context).map_err(internal)?;
+    ssl.set_verify_callback(context.verify_mode(), move |verified, store| {
+        // depth zero is the peer's own certificate, the rest is its chain
+        if verified && store.error_depth() == 0 {
+            match store.current_cert().and_then(common_name) {
+                Some(name) => {
+                    debug!("client presented a certificate for {}", name);
+                    on_peer(name);
+                }
+                None => warn!("client certificate has no common name"),
+            }
+        }
+
+        verified
+    });
+
+    ssl.accept(sock).map_err(From::from)
+}
+
+/// Starts the TLS handshake with the castle.
+pub fn connect(
+    connector: &SslConnector,
+    sock: TcpStream,
+    url: &url::Url,
+) -> ws::Result<SslStream<TcpStream>> {
+    let domain = url
+        .host_str()
+        .ok_or_else(|| ws::Error::new(ws::ErrorKind::Internal, "castle URL has no host"))?;
+
+    connector
+        .configure()
+        .map_err(internal)?
+        .connect(domain, sock)
+        .map_err(From::from)
+}
+
+fn common_name(cert: &X509Ref) -> Option<String> {
+    cert.subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .and_then(|entry| entry.data().as_utf8().ok())
+        .map(|name| name.to_string())
+}
+
+fn internal(err: ErrorStack) -> ws::Error {
+    ws::Error::new(ws::ErrorKind::Internal, err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::ssl::HandshakeError;
+    use std::fs;
+    use std::net::{TcpListener, TcpStream as StdTcpStream};
+    use std::path::PathBuf;
+    use std::process::Command;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    const CONFIG: &str = "
+[req]
+distinguished_name = dn
+[dn]
+[ca]
+basicConstraints = critical, CA:TRUE
+keyUsage = critical, keyCertSign, cRLSign
+[leaf]
+basicConstraints = CA:FALSE
+subjectAltName = DNS:localhost
+";
+
+    const NEW_KEY: &[&str] = &[
+        "-newkey",
+        "ec",
+        "-pkeyopt",
+        "ec_paramgen_curve:prime256v1",
+        "-nodes",
+        "-config",
+        "openssl.cnf",
+    ];
+
+    /// A throwaway CA, with a certificate for the castle on localhost and one for a client.
+    struct Pki {
+        dir: TempDir,
+    }
+
+    impl Pki {
+        fn new() -> Self {
+            let dir = TempDir::new().expect("tempdir");
+            fs::write(dir.path().join("openssl.cnf"), CONFIG).expect("write openssl config");
+
+            let pki = Self { dir };
+            let mut ca = vec![
+                "req",
+                "-x509",
+                "-new",
+                "-days",
+                "1",
+                "-subj",
+                "/CN=Test CA",
+                "-extensions",
+                "ca",
+                "-keyout",
+                "ca.key",
+                "-out",
+                "ca.pem",
+            ];
+            ca.extend(NEW_KEY);
+            pki.openssl(&ca);
+            pki.issue("castle", "localhost");
+            pki.issue("client", "target-one");
+            pki
+        }
+
+        fn issue(&self, name: &str, common_name: &str) {
+            let key = format!("{}.key", name);
+            let csr = format!("{}.csr", name);
+            let cert = format!("{}.pem", name);
+            let subject = format!("/CN={}", common_name);
+
+            let mut req = vec![
+                "req",
+                "-new",
+                "-subj",
+                subject.as_str(),
+                "-keyout",
+                key.as_str(),
+                "-out",
+                csr.as_str(),
+            ];
+            req.extend(NEW_KEY);
+            self.openssl(&req);
+            self.openssl(&[
+                "x509",
+                "-req",
+                "-days",
+                "1",
+                "-set_serial",
+                "2",
+                "-in",
+                csr.as_str(),
+                "-CA",
+                "ca.pem",
+                "-CAkey",
+                "ca.key",
+                "-extfile",
+                "openssl.cnf",
+                "-extensions",
+                "leaf",
+                "-out",
+                cert.as_str(),
+            ]);
+        }
+
+        fn path(&self, file: &str) -> PathBuf {
+            self.dir.path().join(file)
+        }
+
+        fn openssl(&self, args: &[&str]) {
+            let out = Command::new("openssl")
+                .args(args)
+                .current_dir(self.dir.path())
+                .output()
+                .expect("run openssl");
+            assert!(
+                out.status.success(),
+                "openssl {:?} failed: {}",
+                args,
+                String::from_utf8_lossy(&out.stderr)
+            );
+        }
+
+        fn acceptor(&self, mutual: bool) -> SslAcceptor {
+            let ca = self.path("ca.pem");
+            acceptor(
+                &self.path("castle.pem"),
+                &self.path("castle.key"),
+                if mutual { Some(ca.as_path()) } else { None },
+            )
+            .expect("build acceptor")
+        }
+
+        fn connector(&self, identity: bool) -> SslConnector {
+            let cert = self.path("client.pem");
+            let key = self.path("client.key");
+            connector(
+                Some(self.path("ca.pem").as_path()),
+                if identity {
+                    Some((cert.as_path(), key.as_path()))
+                } else {
+                    None
+                },
+            )
+            .expect("build connector")
+        }
+    }
+
+    /// Drives a handshake on a non-blocking socket to the end.
+    fn complete(res: ws::Result<SslStream<TcpStream>>) -> Result<SslStream<TcpStream>, String> {
+        let mut mid = match res {
+            Ok(stream) => return Ok(stream),
+            Err(ws::Error {
+                kind: ws::ErrorKind::SslHandshake(HandshakeError::WouldBlock(mid)),
+                ..
+            }) => mid,
+            Err(err) => return Err(err.to_string()),
+        };
+
+        loop {
+            match mid.handshake() {
+                Ok(stream) => return Ok(stream),
+                Err(HandshakeError::WouldBlock(next)) => {
+                    mid = next;
+                    thread::sleep(Duration::from_millis(1));
+                }
+                Err(err) => return Err(err.to_string()),
+            }
+        }
+    }
+
+    struct Outcome {
+        castle: Result<(), String>,
+        client: Result<(), String>,
+        peer: Option<String>,
+    }
+
+    fn handshake(acceptor: SslAcceptor, connector: &SslConnector) -> Outcome {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let port = listener.local_addr().expect("local address").port();
+        let peer = Arc::new(Mutex::new(None));
+
+        let castle = {
+            let peer = peer.clone();
+            thread::spawn(move || {
+                let (sock, _) = listener.accept().expect("accept");
+                let sock = TcpStream::from_stream(sock).expect("mio stream");
+                complete(accept(&acceptor, sock, move |name| {
+                    *peer.lock().expect("peer poisoned") = Some(name)
+                }))
+                .map(drop)
+            })
+        };
+
+        let url = url::Url::parse(&format!("wss://localhost:{}", port)).expect("url");
+        let sock = StdTcpStream::connect(("127.0.0.1", port)).expect("connect");
+        let sock = TcpStream::from_stream(sock).expect("mio stream");
+
+        // keep the client side open until the castle is done with the handshake
+        let client = complete(connect(connector, sock, &url));
+        let castle = castle.join().expect("castle thread panicked");
+
+        let peer = peer.lock().expect("peer poisoned").take();
+        Outcome {
+            castle,
+            client: client.map(drop),
+            peer,
+        }
+    }
+
+    #[test]
+    fn handshake_without_client_certificate() {
+        let pki = Pki::new();
+        let outcome = handshake(pki.acceptor(false), &pki.connector(false));
+        assert_eq!(outcome.castle, Ok(()));
+        assert_eq!(outcome.client, Ok(()));
+        assert_eq!(outcome.peer, None);
+    }
+
+    #[test]
+    fn mutual_tls_reports_common_name() {
+        let pki = Pki::new();
+        let outcome = handshake(pki.acceptor(true), &pki.connector(true));
+        assert_eq!(outcome.castle, Ok(()));
+        assert_eq!(outcome.client, Ok(()));
+        assert_eq!(outcome.peer, Some("target-one".into()));
+    }
+
+    #[test]
+    fn mutual_tls_rejects_client_without_certificate() {
+        let pki = Pki::new();
+        let outcome = handshake(pki.acceptor(true), &pki.connector(false));
+        assert!(outcome.castle.is_err());
+        assert_eq!(outcome.peer, None);
+    }
+
+    #[test]
+    fn mutual_tls_rejects_client_from_another_ca() {
+        let pki = Pki::new();
+        let other = Pki::new();
+        let outcome = handshake(pki.acceptor(true), &other.connector(true));
+        assert!(outcome.castle.is_err());
+        assert_eq!(outcome.peer, None);
+    }
+
+    #[test]
+    fn client_rejects_castle_from_another_ca() {
+        let pki = Pki::new();
+        let other = Pki::new();
+        let outcome = handshake(pki.acceptor(false), &other.connector(false));
+        assert!(outcome.client.is_err());
+        assert!(outcome.castle.is_err());
+    }
+
+    #[test]
+    fn acceptor_checks_key_matches_certificate() {
+        let pki = Pki::new();
+        assert!(acceptor(&pki.path("castle.pem"), &pki.path("client.key"), None).is_err());
+        assert!(connector(
+            None,
+            Some((
+                pki.path("client.pem").as_path(),
+                pki.path("castle.key").as_path(),
+            ))
+        )
+        .is_err());
+    }
+}